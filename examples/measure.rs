@@ -0,0 +1,179 @@
+//! Measures loading a large comment pool: how long it takes, and how much
+//! memory it holds at its peak and once loaded, compared with storing one
+//! struct with an owned text per comment as before.
+//!
+//! Run with `cargo run --release --example measure`.
+
+use danmaku::{
+    comments::Comments,
+    danmaku::{Danmaku, Mode, Positioned, Source},
+};
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    mem::size_of_val,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+use unicode_segmentation::UnicodeSegmentation;
+
+struct Counting;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+fn grow(bytes: usize) {
+    let allocated = ALLOCATED.fetch_add(bytes, Ordering::Relaxed) + bytes;
+    PEAK.fetch_max(allocated, Ordering::Relaxed);
+}
+
+fn shrink(bytes: usize) {
+    ALLOCATED.fetch_sub(bytes, Ordering::Relaxed);
+}
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        grow(layout.size());
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        shrink(layout.size());
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        if new_size > layout.size() {
+            grow(new_size - layout.size());
+        } else {
+            shrink(layout.size() - new_size);
+        }
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+/// How comments were stored before, one struct with an owned text and layout
+/// state each.
+#[allow(dead_code)]
+struct Previous {
+    message: String,
+    count: usize,
+    lines: usize,
+    time: f64,
+    r: u8,
+    g: u8,
+    b: u8,
+    source: Source,
+    mode: Option<Box<Positioned>>,
+    size: f64,
+    x: Option<f64>,
+    row: Option<usize>,
+}
+
+struct Measured<T> {
+    value: T,
+    time: Duration,
+    /// The bytes held on the heap once `f` returns.
+    bytes: usize,
+    /// The most bytes held on the heap while `f` ran.
+    peak: usize,
+}
+
+fn measure<T>(f: impl FnOnce() -> T) -> Measured<T> {
+    let before = ALLOCATED.load(Ordering::Relaxed);
+    PEAK.store(before, Ordering::Relaxed);
+    let start = Instant::now();
+    let value = f();
+    let time = start.elapsed();
+    Measured {
+        bytes: ALLOCATED.load(Ordering::Relaxed) - before + size_of_val(&value),
+        peak: PEAK.load(Ordering::Relaxed) - before,
+        value,
+        time,
+    }
+}
+
+/// 200k comments over 24 minutes, where most are one of a few hundred common
+/// phrases as in real pools.
+fn fixture() -> Vec<(String, f64, u32)> {
+    (0..200_000u32)
+        .map(|i| {
+            let message = if i % 5 < 3 {
+                format!("哈哈哈哈 {}", i % 400)
+            } else {
+                format!("comment {} 这集太好看了", i)
+            };
+            let time = f64::from(i.wrapping_mul(7919) % 144_000) / 100.;
+            (message, time, i.wrapping_mul(2654435761) & 0xffffff)
+        })
+        .collect()
+}
+
+fn main() {
+    let fixture = fixture();
+
+    let previous = measure(|| {
+        let mut previous = fixture
+            .iter()
+            .map(|&(ref message, time, color)| Previous {
+                count: message
+                    .split('\n')
+                    .map(|line| line.graphemes(true).count())
+                    .max()
+                    .unwrap_or_default(),
+                lines: message.split('\n').count(),
+                message: message.replace('\n', "\\N"),
+                time,
+                r: (color >> 16) as u8,
+                g: (color >> 8) as u8,
+                b: color as u8,
+                source: Source::Dandanplay,
+                mode: None,
+                size: 1.,
+                x: None,
+                row: None,
+            })
+            .collect::<Vec<_>>();
+        previous.sort_by(|a, b| a.time.total_cmp(&b.time));
+        previous
+    });
+
+    let comments = measure(|| {
+        fixture
+            .iter()
+            .map(|(message, time, color)| {
+                Danmaku::new(
+                    message.as_str(),
+                    *time,
+                    Mode::Scroll,
+                    1.,
+                    *color,
+                    Source::Dandanplay,
+                )
+            })
+            .collect::<Comments>()
+    });
+
+    assert!(comments
+        .value
+        .iter()
+        .zip(&previous.value)
+        .all(|(entry, previous)| entry.time == previous.time && entry.message == previous.message));
+    let n = comments.value.len();
+    println!("{} comments", n);
+    for (name, time, bytes, peak) in [
+        ("previous", previous.time, previous.bytes, previous.peak),
+        ("compact", comments.time, comments.bytes, comments.peak),
+    ] {
+        println!(
+            "{:>8}: {:>8.2?}, {:>9} bytes ({} per comment), peak {:>9} bytes",
+            name,
+            time,
+            bytes,
+            bytes / n,
+            peak
+        );
+    }
+}
@@ -0,0 +1,253 @@
+use crate::{
+    comments::Comments,
+    danmaku::{Danmaku, Source},
+    error::{Error, Result},
+    log::log_error,
+};
+use roxmltree::{Document, Node};
+use std::{
+    fs::read_to_string,
+    io::{self, ErrorKind},
+    path::PathBuf,
+};
+use tokio::task::spawn_blocking;
+
+/// The font size of normal comments in Bilibili's units, which niconico's
+/// `small`, `medium` and `big` map to as 18, 25 and 36.
+const NORMAL_SIZE: f64 = 25.;
+
+/// Reads the Bilibili or niconico XML comment file with the same name as the
+/// video, if there is one.
+pub async fn read_sidecar(path: PathBuf) -> Result<Option<(Comments, usize)>> {
+    spawn_blocking(move || {
+        let xml = match read_to_string(path.with_extension("xml")) {
+            Ok(xml) => xml,
+            Err(error) if error.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(error.into()),
+        };
+        parse_xml(&xml, Source::Local).map(Some)
+    })
+    .await
+    .map_err(io::Error::other)?
+}
+
+/// Parses comments in Bilibili's or niconico's XML format, sorted by time,
+/// dropping and counting the malformed ones.
+pub fn parse_xml(xml: &str, source: Source) -> Result<(Comments, usize)> {
+    let document = Document::parse(xml).map_err(|error| Error::Parse(error.to_string()))?;
+    let mut skipped = 0;
+    let comments = document
+        .descendants()
+        .filter(|node| node.has_tag_name("d") || node.has_tag_name("chat"))
+        .filter_map(|node| {
+            let message = node.text().unwrap_or_default();
+            let danmaku = if node.has_tag_name("d") {
+                node.attribute("p")
+                    .and_then(|p| parse_comment(p, message, source))
+            } else {
+                parse_chat(node, message, source)
+            };
+            if danmaku.is_none() {
+                skipped += 1;
+            }
+            danmaku
+        })
+        .collect::<Comments>();
+    if skipped > 0 {
+        unsafe { log_error(Error::Malformed(skipped)) };
+    }
+    Ok((comments, skipped))
+}
+
+/// Parses a comment whose `p` attribute is `time,mode,size,color,...`.
+fn parse_comment<'a>(p: &str, message: &'a str, source: Source) -> Option<Danmaku<'a>> {
+    let mut p = p.split(',');
+    let time = p.next()?.parse::<f64>().ok().filter(|t| t.is_finite())?;
+    let mode = p.next()?.parse::<u8>().ok()?;
+    // 25 is the normal size, 18 small and 36 big.
+    let size = p
+        .next()?
+        .parse::<f64>()
+        .ok()
+        .filter(|&s| s > 0. && s < 250.)?
+        / NORMAL_SIZE;
+    let color = p.next()?.parse::<u32>().ok().filter(|&c| c <= 0xffffff)?;
+    Danmaku::parse(message, time, mode, size, color, source)
+}
+
+/// Parses a niconico comment, whose `vpos` attribute is the time in hundredths
+/// of a second and whose `mail` attribute holds commands for its position,
+/// size and color.
+fn parse_chat<'a>(node: Node, message: &'a str, source: Source) -> Option<Danmaku<'a>> {
+    let time = node
+        .attribute("vpos")?
+        .parse::<f64>()
+        .ok()
+        .filter(|t| t.is_finite())?
+        / 100.;
+    let (mut mode, mut size, mut color) = (1, NORMAL_SIZE, 0xffffff);
+    for command in node
+        .attribute("mail")
+        .unwrap_or_default()
+        .split_whitespace()
+    {
+        match command {
+            "naka" => mode = 1,
+            "shita" => mode = 4,
+            "ue" => mode = 5,
+            "small" => size = 18.,
+            "medium" => size = NORMAL_SIZE,
+            "big" => size = 36.,
+            _ => {
+                if let Some(named) = nico_color(command) {
+                    color = named;
+                }
+            }
+        }
+    }
+    Danmaku::parse(message, time, mode, size / NORMAL_SIZE, color, source)
+}
+
+/// Returns the color of a niconico color command, either a name or `#RRGGBB`.
+fn nico_color(command: &str) -> Option<u32> {
+    Some(match command {
+        "white" => 0xffffff,
+        "red" => 0xff0000,
+        "pink" => 0xff8080,
+        "orange" => 0xffc000,
+        "yellow" => 0xffff00,
+        "green" => 0x00ff00,
+        "cyan" => 0x00ffff,
+        "blue" => 0x0000ff,
+        "purple" => 0xc000ff,
+        "black" => 0x000000,
+        _ => {
+            u32::from_str_radix(command.strip_prefix('#').filter(|hex| hex.len() == 6)?, 16).ok()?
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::danmaku::{Mode, Positioned};
+
+    const MALFORMED: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<i>
+    <chatserver>chat.bilibili.com</chatserver>
+    <d p="12.5,1,25,16777215,1700000000,0,abcdef,1">normal</d>
+    <d p="3.0,5,36,255,1700000000,0,abcdef,2">big &amp; top</d>
+    <d p="7.0,4,18,65280,1700000000,0,abcdef,3">small</d>
+    <d p="">empty</d>
+    <d p="1.0,1">short</d>
+    <d p="x,1,25,255">bad time</d>
+    <d p="1.0,1,-25,255">bad size</d>
+    <d p="1.0,1,25,16777216">bad color</d>
+    <d>no p</d>
+</i>"#;
+
+    #[test]
+    fn malformed() {
+        let (comments, skipped) = parse_xml(MALFORMED, Source::Local).unwrap();
+        let danmaku = comments.iter().collect::<Vec<_>>();
+        assert_eq!(skipped, 6);
+        assert_eq!(
+            danmaku
+                .iter()
+                .map(|danmaku| (danmaku.time, danmaku.mode, danmaku.size, danmaku.message))
+                .collect::<Vec<_>>(),
+            [
+                (3., Mode::Top, 1.44, "big & top"),
+                (7., Mode::Bottom, 0.72, "small"),
+                (12.5, Mode::Scroll, 1., "normal"),
+            ]
+        );
+        assert_eq!((danmaku[0].r, danmaku[0].g, danmaku[0].b), (0, 0, 255));
+    }
+
+    const POSITIONED: &str = r#"<i>
+    <d p="1.0,7,25,16777215">[0.1,0.2,"1-0.5","4.5","line/nbreak",90,0,"0.5","0.6",2000,500,0,"SimHei",1]</d>
+    <d p="2.0,7,25,16777215">[341,219,"1",3,"pixels"]</d>
+    <d p="3.0,7,25,16777215">not json</d>
+    <d p="4.0,7,25,16777215">[0.1,0.2,"1-1",0,"zero duration"]</d>
+    <d p="5.0,7,25,16777215">[0.1,0.2,"1-1",3]</d>
+    <d p="6.0,7,25,16777215">[0.1,0.2,"1",3600,"an hour"]</d>
+</i>"#;
+
+    #[test]
+    fn positioned() {
+        let (comments, skipped) = parse_xml(POSITIONED, Source::Local).unwrap();
+        let danmaku = comments.iter().collect::<Vec<_>>();
+        assert_eq!(skipped, 3);
+        assert_eq!(danmaku.len(), 3);
+        assert_eq!(danmaku[0].message, "line\\Nbreak");
+        assert_eq!(danmaku[0].mode, Mode::Positioned);
+        assert_eq!(
+            danmaku[0].effect,
+            Some(&Positioned {
+                start: (0.1, 0.2),
+                end: (0.5, 0.6),
+                alpha: (1., 0.5),
+                duration: 4.5,
+                rotate_z: 90.,
+                rotate_y: 0.,
+                move_duration: 2.,
+                move_delay: 0.5,
+                accelerate: true,
+            })
+        );
+        assert_eq!(danmaku[1].message, "pixels");
+        assert_eq!(
+            danmaku[1].effect,
+            Some(&Positioned {
+                start: (0.5, 0.5),
+                end: (0.5, 0.5),
+                alpha: (1., 1.),
+                duration: 3.,
+                rotate_z: 0.,
+                rotate_y: 0.,
+                move_duration: 3.,
+                move_delay: 0.,
+                accelerate: false,
+            })
+        );
+        assert_eq!(danmaku[2].effect.map(|effect| effect.duration), Some(60.));
+        assert_eq!(comments.longest(), 60.);
+    }
+
+    const NICONICO: &str = r#"<packet>
+    <chat thread="1" no="1" vpos="150" mail="184">normal</chat>
+    <chat thread="1" no="2" vpos="300" mail="ue big red">big &amp; top</chat>
+    <chat thread="1" no="3" vpos="700" mail="shita small #00ff00">small</chat>
+    <chat thread="1" no="4" mail="ue">no vpos</chat>
+</packet>"#;
+
+    #[test]
+    fn niconico() {
+        let (comments, skipped) = parse_xml(NICONICO, Source::Local).unwrap();
+        assert_eq!(skipped, 1);
+        assert_eq!(
+            comments
+                .iter()
+                .map(|entry| (
+                    entry.time,
+                    entry.mode,
+                    entry.size,
+                    entry.message,
+                    entry.r,
+                    entry.g
+                ))
+                .collect::<Vec<_>>(),
+            [
+                (1.5, Mode::Scroll, 1., "normal", 255, 255),
+                (3., Mode::Top, 1.44, "big & top", 255, 0),
+                (7., Mode::Bottom, 0.72, "small", 0, 255),
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(parse_xml("<i><d p=\"1,1,25,0\">unclosed</i>", Source::Local).is_err());
+    }
+}
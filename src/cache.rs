@@ -0,0 +1,320 @@
+use crate::{error::Result, log::log_error, options::expand_path, CLIENT_NAME};
+use hex::encode;
+use md5::{Digest, Md5};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    collections::HashMap,
+    fs::{
+        canonicalize, create_dir_all, metadata, read_dir, remove_file, rename, File, OpenOptions,
+    },
+    io::{self, copy, BufRead, BufReader, BufWriter, ErrorKind, Read, Write},
+    path::{Path, PathBuf},
+    sync::{Mutex, OnceLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::task::spawn_blocking;
+
+struct HashEntry {
+    size: u64,
+    mtime: u128,
+    hash: String,
+}
+
+struct FolderEntry {
+    anime_id: usize,
+    offset: i64,
+}
+
+static HASHES: OnceLock<Mutex<HashMap<PathBuf, HashEntry>>> = OnceLock::new();
+static FOLDERS: OnceLock<Mutex<HashMap<(PathBuf, String), FolderEntry>>> = OnceLock::new();
+
+/// Returns the MD5 of the first 16 MiB of the file and its size, reusing a
+/// previous result if the file's size and modification time are unchanged.
+pub async fn file_hash(path: PathBuf) -> io::Result<(String, u64)> {
+    spawn_blocking(move || unsafe { cached_hash(&path) })
+        .await
+        .map_err(io::Error::other)?
+}
+
+unsafe fn cached_hash(path: &Path) -> io::Result<(String, u64)> {
+    let path = canonicalize(path)?;
+    let metadata = metadata(&path)?;
+    let size = metadata.len();
+    let mtime = metadata
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+
+    if let Some(entry) = hashes().lock().unwrap().get(&path) {
+        if entry.size == size && entry.mtime == mtime {
+            return Ok((entry.hash.clone(), size));
+        }
+    }
+
+    let mut hasher = Md5::new();
+    // https://api.dandanplay.net/swagger/ui/index
+    copy(&mut File::open(&path)?.take(16 * 1024 * 1024), &mut hasher)?;
+    let hash = encode(hasher.finalize());
+    if let Some(line) = path
+        .to_str()
+        .filter(|path| !path.contains('\n'))
+        .map(|path| format!("{}\t{}\t{}\t{}", size, mtime, hash, path))
+    {
+        if let Err(error) = append_line("hashes", &line) {
+            log_error(error);
+        }
+    }
+    hashes().lock().unwrap().insert(
+        path,
+        HashEntry {
+            size,
+            mtime,
+            hash: hash.clone(),
+        },
+    );
+    Ok((hash, size))
+}
+
+unsafe fn hashes() -> &'static Mutex<HashMap<PathBuf, HashEntry>> {
+    HASHES.get_or_init(|| {
+        Mutex::new(load_hashes().unwrap_or_else(|error| {
+            log_error(error);
+            HashMap::new()
+        }))
+    })
+}
+
+/// Loads the recorded hashes, compacting the file if it holds entries that
+/// were superseded or whose files no longer exist.
+unsafe fn load_hashes() -> Result<HashMap<PathBuf, HashEntry>> {
+    let mut hashes = HashMap::<PathBuf, _>::new();
+    let lines = read_lines("hashes")?;
+    for line in &lines {
+        let mut fields = line.splitn(4, '\t');
+        let (Some(size), Some(mtime), Some(hash), Some(path)) =
+            (fields.next(), fields.next(), fields.next(), fields.next())
+        else {
+            continue;
+        };
+        let (Ok(size), Ok(mtime)) = (size.parse(), mtime.parse()) else {
+            continue;
+        };
+        hashes.insert(
+            path.into(),
+            HashEntry {
+                size,
+                mtime,
+                hash: hash.into(),
+            },
+        );
+    }
+    hashes.retain(|path, _| path.exists());
+    if hashes.len() < lines.len() {
+        let lines = hashes
+            .iter()
+            .filter_map(|(path, entry)| {
+                let path = path.to_str()?;
+                Some(format!(
+                    "{}\t{}\t{}\t{}",
+                    entry.size, entry.mtime, entry.hash, path
+                ))
+            })
+            .collect::<Vec<_>>();
+        write_lines("hashes", &lines)?;
+    }
+    Ok(hashes)
+}
+
+/// Returns the anime and episode offset recorded for files of the given title
+/// in the given directory.
+pub unsafe fn folder_anime(dir: &Path, title: &str) -> Option<(usize, i64)> {
+    folders()
+        .lock()
+        .unwrap()
+        .get(&(dir.to_path_buf(), title.to_string()))
+        .map(|entry| (entry.anime_id, entry.offset))
+}
+
+pub unsafe fn set_folder_anime(
+    dir: &Path,
+    title: &str,
+    anime_id: usize,
+    offset: i64,
+) -> Result<()> {
+    let mut folders = folders().lock().unwrap();
+    let key = (dir.to_path_buf(), title.to_string());
+    if folders
+        .get(&key)
+        .is_some_and(|entry| entry.anime_id == anime_id && entry.offset == offset)
+    {
+        return Ok(());
+    }
+    if let Some(dir) = dir
+        .to_str()
+        .filter(|dir| !dir.contains('\n') && !title.contains(['\t', '\n']))
+    {
+        append_line(
+            "folders",
+            &format!("{}\t{}\t{}\t{}", anime_id, offset, title, dir),
+        )?;
+    }
+    folders.insert(key, FolderEntry { anime_id, offset });
+    Ok(())
+}
+
+unsafe fn folders() -> &'static Mutex<HashMap<(PathBuf, String), FolderEntry>> {
+    FOLDERS.get_or_init(|| {
+        Mutex::new(load_folders().unwrap_or_else(|error| {
+            log_error(error);
+            HashMap::new()
+        }))
+    })
+}
+
+/// Loads the recorded folders, compacting the file like [`load_hashes`].
+unsafe fn load_folders() -> Result<HashMap<(PathBuf, String), FolderEntry>> {
+    let mut folders = HashMap::<(PathBuf, String), _>::new();
+    let lines = read_lines("folders")?;
+    for line in &lines {
+        let mut fields = line.splitn(4, '\t');
+        let (Some(anime_id), Some(offset), Some(title), Some(dir)) =
+            (fields.next(), fields.next(), fields.next(), fields.next())
+        else {
+            continue;
+        };
+        let (Ok(anime_id), Ok(offset)) = (anime_id.parse(), offset.parse()) else {
+            continue;
+        };
+        folders.insert((dir.into(), title.into()), FolderEntry { anime_id, offset });
+    }
+    folders.retain(|(dir, _), _| dir.exists());
+    if folders.len() < lines.len() {
+        let lines = folders
+            .iter()
+            .filter_map(|((dir, title), entry)| {
+                let dir = dir.to_str()?;
+                Some(format!(
+                    "{}\t{}\t{}\t{}",
+                    entry.anime_id, entry.offset, title, dir
+                ))
+            })
+            .collect::<Vec<_>>();
+        write_lines("folders", &lines)?;
+    }
+    Ok(folders)
+}
+
+/// Reads a JSON file from the cache directory, returning `None` if it does not
+/// exist or cannot be read.
+pub async fn read_cache<T: DeserializeOwned + Send + 'static>(name: String) -> Option<T> {
+    spawn_blocking(move || unsafe {
+        read_json(&name).unwrap_or_else(|error| {
+            log_error(error);
+            None
+        })
+    })
+    .await
+    .ok()
+    .flatten()
+}
+
+pub async fn write_cache<T: Serialize + Send + 'static>(name: String, value: T) {
+    _ = spawn_blocking(move || unsafe {
+        if let Err(error) = write_json(&name, &value) {
+            log_error(error);
+        }
+    })
+    .await;
+}
+
+/// Deletes the cached comments and episode lists that have not been updated
+/// for longer than `expiry`.
+pub async fn evict_cache(expiry: Duration) {
+    _ = spawn_blocking(move || unsafe {
+        if let Err(error) = evict(expiry) {
+            log_error(error);
+        }
+    })
+    .await;
+}
+
+unsafe fn evict(expiry: Duration) -> Result<()> {
+    let dir = cache_dir()?;
+    let now = SystemTime::now();
+    for name in ["comments", "bangumi"] {
+        let entries = match read_dir(dir.join(name)) {
+            Ok(entries) => entries,
+            Err(error) if error.kind() == ErrorKind::NotFound => continue,
+            Err(error) => return Err(error.into()),
+        };
+        for entry in entries {
+            let entry = entry?;
+            let modified = entry.metadata()?.modified()?;
+            if now.duration_since(modified).is_ok_and(|age| age > expiry) {
+                remove_file(entry.path())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+unsafe fn read_json<T: DeserializeOwned>(name: &str) -> Result<Option<T>> {
+    let file = match File::open(cache_dir()?.join(name)) {
+        Ok(file) => file,
+        Err(error) if error.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(error) => return Err(error.into()),
+    };
+    Ok(Some(serde_json::from_reader(BufReader::new(file))?))
+}
+
+unsafe fn write_json<T: Serialize>(name: &str, value: &T) -> Result<()> {
+    let path = cache_dir()?.join(name);
+    if let Some(dir) = path.parent() {
+        create_dir_all(dir)?;
+    }
+    let temp = path.with_extension("tmp");
+    let mut writer = BufWriter::new(File::create(&temp)?);
+    serde_json::to_writer(&mut writer, value)?;
+    writer.flush()?;
+    rename(temp, path)?;
+    Ok(())
+}
+
+unsafe fn cache_dir() -> Result<PathBuf> {
+    let client_name = CLIENT_NAME;
+    let dir = PathBuf::from(expand_path(&format!("~~cache/{}", client_name))?);
+    create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+unsafe fn read_lines(name: &str) -> Result<Vec<String>> {
+    let file = match File::open(cache_dir()?.join(name)) {
+        Ok(file) => file,
+        Err(error) if error.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(error) => return Err(error.into()),
+    };
+    Ok(BufReader::new(file).lines().collect::<io::Result<_>>()?)
+}
+
+/// Replaces the file with the given lines.
+unsafe fn write_lines(name: &str, lines: &[String]) -> Result<()> {
+    let path = cache_dir()?.join(name);
+    let temp = path.with_extension("tmp");
+    let mut writer = BufWriter::new(File::create(&temp)?);
+    for line in lines {
+        writeln!(writer, "{}", line)?;
+    }
+    writer.flush()?;
+    rename(temp, path)?;
+    Ok(())
+}
+
+unsafe fn append_line(name: &str, line: &str) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(cache_dir()?.join(name))?;
+    writeln!(file, "{}", line)?;
+    Ok(())
+}
@@ -0,0 +1,581 @@
+use crate::danmaku::{Danmaku, Mode, Positioned, Source};
+use std::{
+    borrow::Cow,
+    collections::{hash_map::Entry as Slot, HashMap},
+    hash::{BuildHasherDefault, Hasher},
+    iter::once,
+    mem::swap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+use unicode_segmentation::UnicodeSegmentation;
+
+/// Comments sorted by time in a compact form: each distinct text is stored
+/// once in a shared buffer, and the rest of a comment is packed into a small
+/// fixed-size record.
+///
+/// The comments never change once loaded. Merging in new ones appends them as
+/// another chunk, so cloning a pool only copies the list of chunks, while
+/// where the comments are placed on screen is kept apart in [`Layouts`].
+#[derive(Clone)]
+pub struct Comments {
+    /// Identifies the pool, so that layouts are not carried over to another.
+    pool: u64,
+    /// Chunks of comments sorted by time, with the id of their first comment.
+    chunks: Vec<(u32, Arc<Chunk>)>,
+    len: usize,
+    /// The longest duration of the positioned comments.
+    longest: f64,
+    /// The largest length of the scrolling comments, in font sizes.
+    widest: f64,
+}
+
+#[derive(Default)]
+struct Chunk {
+    records: Vec<Record>,
+    texts: Vec<Text>,
+    buffer: String,
+    effects: Vec<Positioned>,
+    longest: f64,
+    widest: f64,
+}
+
+struct Record {
+    time: f64,
+    /// The order in which the comment was added to the chunk.
+    id: u32,
+    text: u32,
+    effect: u32,
+    color: [u8; 3],
+    source: Source,
+    mode: Mode,
+    /// The font size in Bilibili's units, where 25 is the normal size.
+    size: u8,
+}
+
+struct Text {
+    start: u32,
+    len: u32,
+    count: u16,
+    lines: u16,
+}
+
+/// Maps the hashes of the texts to their indices while a chunk is built.
+type Interned = HashMap<u32, u32, BuildHasherDefault<Identity>>;
+
+/// Where a comment was placed on screen. Its position along the row follows
+/// from the time.
+#[derive(Clone, Copy)]
+pub struct Layout {
+    pub row: u16,
+}
+
+/// A comment borrowed from [`Comments`].
+pub struct Entry<'a> {
+    /// The order in which the comment was added, which stays the same as
+    /// others are merged in.
+    pub id: u32,
+    /// The text, with line breaks as `\N`.
+    pub message: &'a str,
+    /// The number of graphemes in the longest line.
+    pub count: usize,
+    pub lines: usize,
+    pub time: f64,
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+    pub source: Source,
+    pub mode: Mode,
+    pub effect: Option<&'a Positioned>,
+    /// The font size relative to the configured one.
+    pub size: f64,
+}
+
+/// The layout of the comments drawn in the last frame. Comments that were
+/// not drawn again are forgotten when a frame finishes.
+#[derive(Default)]
+pub struct Layouts {
+    pool: u64,
+    current: HashMap<u32, Layout>,
+    next: HashMap<u32, Layout>,
+    /// The index of the first comment in the window of the last frame, for
+    /// each chunk.
+    cursors: Vec<Option<usize>>,
+}
+
+const NO_EFFECT: u32 = u32::MAX;
+/// The largest length of a scrolling comment that is kept track of, in font
+/// sizes. Longer ones may leave the screen early, rather than every frame
+/// looking back for as long as they take to scroll past.
+const WIDEST: f64 = 100.;
+
+static POOLS: AtomicU64 = AtomicU64::new(1);
+
+impl Default for Comments {
+    /// An empty pool, which gets an id of its own like any other, as comments
+    /// may be appended to it.
+    fn default() -> Self {
+        Self {
+            pool: POOLS.fetch_add(1, Ordering::Relaxed),
+            chunks: Vec::new(),
+            len: 0,
+            longest: 0.,
+            widest: 0.,
+        }
+    }
+}
+
+impl Comments {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Iterates over all comments sorted by time.
+    pub fn iter(&self) -> impl Iterator<Item = Entry<'_>> {
+        sorted(
+            self.chunks
+                .iter()
+                .flat_map(|(base, chunk)| chunk.records.iter().map(|r| chunk.entry(*base, r)))
+                .collect(),
+            self.chunks.len(),
+        )
+        .into_iter()
+    }
+
+    /// The longest a positioned comment stays on screen, in seconds.
+    pub fn longest(&self) -> f64 {
+        self.longest
+    }
+
+    /// The length of the longest scrolling comment, in multiples of the font
+    /// size.
+    pub fn widest(&self) -> f64 {
+        self.widest
+    }
+
+    /// Merges in the comments of another pool without copying either.
+    pub fn append(&mut self, other: Comments) {
+        let base = self.len as u32;
+        self.chunks.extend(
+            other
+                .chunks
+                .into_iter()
+                .map(|(start, chunk)| (base + start, chunk)),
+        );
+        self.len += other.len;
+        self.longest = self.longest.max(other.longest);
+        self.widest = self.widest.max(other.widest);
+    }
+
+    /// Adds a comment after those at the same time.
+    pub fn insert(&mut self, danmaku: Danmaku) {
+        self.append(once(danmaku).collect());
+    }
+}
+
+impl Chunk {
+    fn record(&mut self, interned: &mut Interned, danmaku: Danmaku) -> Record {
+        let effect = match danmaku.effect {
+            Some(effect) => {
+                self.longest = self.longest.max(effect.duration);
+                self.effects.push(*effect);
+                self.effects.len() as u32 - 1
+            }
+            None => NO_EFFECT,
+        };
+        let message = &*danmaku.message;
+        let text = if message.contains('\n') {
+            Cow::Owned(message.replace('\n', "\\N"))
+        } else {
+            Cow::Borrowed(message)
+        };
+        let text = self.intern(interned, &text, || {
+            (
+                message
+                    .split('\n')
+                    .map(graphemes)
+                    .max()
+                    .unwrap_or_default()
+                    .try_into()
+                    .unwrap_or(u16::MAX),
+                message.split('\n').count().try_into().unwrap_or(u16::MAX),
+            )
+        });
+        if matches!(danmaku.mode, Mode::Scroll | Mode::Reverse) {
+            let count = self.texts[text as usize].count;
+            self.widest = self
+                .widest
+                .max((f64::from(count) * danmaku.size).min(WIDEST));
+        }
+        Record {
+            time: danmaku.time,
+            id: self.records.len() as u32,
+            text,
+            effect,
+            color: [danmaku.r, danmaku.g, danmaku.b],
+            source: danmaku.source,
+            mode: danmaku.mode,
+            size: (danmaku.size * 25.).round().clamp(1., 255.) as u8,
+        }
+    }
+
+    /// Returns the index of the text, with line breaks as `\N`, adding it with
+    /// the grapheme and line counts from `measure` if it is new.
+    fn intern(
+        &mut self,
+        interned: &mut Interned,
+        text: &str,
+        measure: impl FnOnce() -> (u16, u16),
+    ) -> u32 {
+        let index = self.texts.len() as u32;
+        match interned.entry(fnv(text.as_bytes(), FNV_OFFSET)) {
+            Slot::Occupied(slot) => {
+                let interned = &self.texts[*slot.get() as usize];
+                let start = interned.start as usize;
+                if self.buffer[start..start + interned.len as usize] == *text {
+                    return *slot.get();
+                }
+                // On a collision the earlier text keeps the hash.
+            }
+            Slot::Vacant(slot) => {
+                slot.insert(index);
+            }
+        }
+
+        let (count, lines) = measure();
+        self.texts.push(Text {
+            start: self.buffer.len() as u32,
+            len: text.len() as u32,
+            count,
+            lines,
+        });
+        self.buffer.push_str(text);
+        index
+    }
+
+    fn entry(&self, base: u32, record: &Record) -> Entry<'_> {
+        let text = &self.texts[record.text as usize];
+        let start = text.start as usize;
+        Entry {
+            id: base + record.id,
+            message: &self.buffer[start..start + text.len as usize],
+            count: text.count.into(),
+            lines: text.lines.into(),
+            time: record.time,
+            r: record.color[0],
+            g: record.color[1],
+            b: record.color[2],
+            source: record.source,
+            mode: record.mode,
+            effect: self.effects.get(record.effect as usize),
+            size: f64::from(record.size) / 25.,
+        }
+    }
+}
+
+/// Sorts entries gathered from several chunks by time, keeping those of
+/// earlier chunks first at the same time.
+fn sorted(mut entries: Vec<Entry<'_>>, chunks: usize) -> Vec<Entry<'_>> {
+    if chunks > 1 {
+        entries.sort_by(|a, b| a.time.total_cmp(&b.time));
+    }
+    entries
+}
+
+/// Counts the graphemes of a line, skipping the segmentation for printable
+/// ASCII, which most comments are.
+fn graphemes(line: &str) -> usize {
+    if line
+        .bytes()
+        .all(|byte| byte.is_ascii_graphic() || byte == b' ')
+    {
+        line.len()
+    } else {
+        line.graphemes(true).count()
+    }
+}
+
+const FNV_OFFSET: u32 = 0x811c9dc5;
+
+/// FNV-1a, which is much faster than the default SipHash on short texts.
+fn fnv(bytes: &[u8], hash: u32) -> u32 {
+    bytes.iter().fold(hash, |hash, &byte| {
+        (hash ^ u32::from(byte)).wrapping_mul(0x01000193)
+    })
+}
+
+/// Spreads the precomputed hashes of the texts over the whole `u64`.
+#[derive(Default)]
+struct Identity(u32);
+
+impl Hasher for Identity {
+    fn finish(&self) -> u64 {
+        u64::from(self.0).wrapping_mul(0x9e3779b97f4a7c15)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        self.0 = fnv(bytes, self.0 ^ FNV_OFFSET);
+    }
+
+    fn write_u32(&mut self, hash: u32) {
+        self.0 = hash;
+    }
+}
+
+impl<'a> FromIterator<Danmaku<'a>> for Comments {
+    /// Builds a pool of one chunk straight from parsed comments, sorting them
+    /// by time.
+    fn from_iter<I: IntoIterator<Item = Danmaku<'a>>>(danmaku: I) -> Self {
+        let danmaku = danmaku.into_iter();
+        let mut chunk = Chunk::default();
+        let mut interned = Interned::default();
+        chunk.records.reserve(danmaku.size_hint().0);
+        for danmaku in danmaku {
+            let record = chunk.record(&mut interned, danmaku);
+            chunk.records.push(record);
+        }
+        chunk.records.sort_by(|a, b| a.time.total_cmp(&b.time));
+        // Nothing is added to a chunk once built.
+        chunk.records.shrink_to_fit();
+        chunk.texts.shrink_to_fit();
+        chunk.buffer.shrink_to_fit();
+        chunk.effects.shrink_to_fit();
+        if chunk.records.is_empty() {
+            return Self::default();
+        }
+        Self {
+            pool: POOLS.fetch_add(1, Ordering::Relaxed),
+            len: chunk.records.len(),
+            longest: chunk.longest,
+            widest: chunk.widest,
+            chunks: vec![(0, Arc::new(chunk))],
+        }
+    }
+}
+
+impl Layouts {
+    /// Starts a frame of the given comments, forgetting the layout of any
+    /// other pool.
+    pub fn start(&mut self, comments: &Comments) {
+        if self.pool != comments.pool {
+            self.reset();
+            self.pool = comments.pool;
+        }
+    }
+
+    /// Returns the comments from `start` to `end` seconds sorted by time,
+    /// moving on in each chunk from where the window of the last frame
+    /// started.
+    pub fn window<'a>(&mut self, comments: &'a Comments, start: f64, end: f64) -> Vec<Entry<'a>> {
+        self.cursors.resize(comments.chunks.len(), None);
+        let mut entries = Vec::new();
+        for ((base, chunk), cursor) in comments.chunks.iter().zip(&mut self.cursors) {
+            let records = &chunk.records;
+            let mut index = match *cursor {
+                // Moving back, as after a seek, finds the window again. A
+                // cursor past the end was left by another pool.
+                Some(index)
+                    if index <= records.len()
+                        && records[..index]
+                            .last()
+                            .map(|r| r.time < start)
+                            .unwrap_or(true) =>
+                {
+                    index
+                }
+                _ => records.partition_point(|record| record.time < start),
+            };
+            while records.get(index).is_some_and(|record| record.time < start) {
+                index += 1;
+            }
+            *cursor = Some(index);
+            entries.extend(
+                records[index..]
+                    .iter()
+                    .take_while(|record| record.time <= end)
+                    .map(|record| chunk.entry(*base, record)),
+            );
+        }
+        sorted(entries, comments.chunks.len())
+    }
+
+    pub fn get(&self, id: u32) -> Option<Layout> {
+        self.current.get(&id).copied()
+    }
+
+    /// Records where a comment was drawn in this frame.
+    pub fn set(&mut self, id: u32, layout: Layout) {
+        self.next.insert(id, layout);
+    }
+
+    pub fn finish(&mut self) {
+        swap(&mut self.current, &mut self.next);
+        self.next.clear();
+    }
+
+    /// Forgets where the comments were placed, and finds the window again in
+    /// the next frame, as after a seek.
+    pub fn reset(&mut self) {
+        self.current.clear();
+        self.next.clear();
+        self.cursors.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn danmaku(message: &str, time: f64) -> Danmaku<'_> {
+        Danmaku::new(message, time, Mode::Scroll, 1., 0xff8000, Source::Local)
+    }
+
+    #[test]
+    fn interned() {
+        let mut comments = [
+            danmaku("b", 2.),
+            danmaku("ab\nこんにちは\nc", 1.),
+            danmaku("b", 0.),
+        ]
+        .into_iter()
+        .collect::<Comments>();
+        assert_eq!(comments.chunks[0].1.texts.len(), 2);
+        comments.insert(danmaku("b", 1.));
+        comments.append([danmaku("b", 3.)].into_iter().collect());
+        assert_eq!((comments.len(), comments.chunks.len()), (5, 3));
+        assert_eq!(
+            comments
+                .iter()
+                .map(|entry| (entry.time, entry.message, entry.count, entry.lines))
+                .collect::<Vec<_>>(),
+            [
+                (0., "b", 1, 1),
+                (1., "ab\\Nこんにちは\\Nc", 5, 3),
+                (1., "b", 1, 1),
+                (2., "b", 1, 1),
+                (3., "b", 1, 1),
+            ]
+        );
+        let entry = comments.iter().next().unwrap();
+        assert_eq!((entry.r, entry.g, entry.b), (0xff, 0x80, 0));
+        assert_eq!(comments.widest(), 5.);
+        comments.insert(danmaku(&"a".repeat(1000), 4.));
+        assert_eq!(comments.widest(), WIDEST);
+    }
+
+    #[test]
+    fn layout() {
+        let mut comments = [danmaku("a", 1.), danmaku("b", 3.)]
+            .into_iter()
+            .collect::<Comments>();
+        let mut layouts = Layouts::default();
+        layouts.start(&comments);
+        for entry in comments.iter() {
+            layouts.set(
+                entry.id,
+                Layout {
+                    row: entry.time as u16,
+                },
+            );
+        }
+        layouts.finish();
+        comments.append([danmaku("c", 2.), danmaku("d", 0.)].into_iter().collect());
+        layouts.start(&comments);
+        assert_eq!(
+            comments
+                .iter()
+                .map(|entry| (
+                    entry.message,
+                    layouts.get(entry.id).map(|layout| layout.row)
+                ))
+                .collect::<Vec<_>>(),
+            [("d", None), ("a", Some(1)), ("c", None), ("b", Some(3))]
+        );
+
+        // Only the comments drawn in the last frame are kept.
+        layouts.set(0, Layout { row: 5 });
+        layouts.finish();
+        assert_eq!(layouts.get(0).map(|layout| layout.row), Some(5));
+        assert!(layouts.get(1).is_none());
+
+        layouts.start(&[danmaku("a", 1.)].into_iter().collect());
+        assert!(layouts.get(0).is_none());
+    }
+
+    #[test]
+    fn shared() {
+        let mut comments = [danmaku("a", 1.)].into_iter().collect::<Comments>();
+        let rendered = comments.clone();
+        comments.append([danmaku("b", 0.), danmaku("c", 2.)].into_iter().collect());
+        // The clone being rendered keeps the chunk, which is not copied.
+        assert!(Arc::ptr_eq(&comments.chunks[0].1, &rendered.chunks[0].1));
+        assert_eq!(rendered.len(), 1);
+        assert_eq!(
+            Layouts::default()
+                .window(&comments, 0., 1.5)
+                .iter()
+                .map(|entry| (entry.id, entry.message))
+                .collect::<Vec<_>>(),
+            [(1, "b"), (0, "a")]
+        );
+    }
+
+    #[test]
+    fn window() {
+        let comments = (0..10)
+            .map(|i| danmaku("a", i.into()))
+            .collect::<Comments>();
+        let mut layouts = Layouts::default();
+        let window = |layouts: &mut Layouts, start, end| {
+            layouts
+                .window(&comments, start, end)
+                .iter()
+                .map(|entry| entry.time)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(window(&mut layouts, 2.5, 4.), [3., 4.]);
+        assert_eq!(window(&mut layouts, 3., 5.5), [3., 4., 5.]);
+        assert_eq!(window(&mut layouts, 8.5, 20.), [9.]);
+        // Moving back, as after a seek, finds the window again.
+        assert_eq!(window(&mut layouts, 0., 1.), [0., 1.]);
+        layouts.reset();
+        assert_eq!(window(&mut layouts, 6., 6.), [6.]);
+        assert_eq!(window(&mut layouts, 20., 30.), [] as [f64; 0]);
+    }
+
+    #[test]
+    fn replaced() {
+        let long = (0..90)
+            .map(|i| danmaku("a", i.into()))
+            .collect::<Comments>();
+        let short = (0..5).map(|i| danmaku("b", i.into())).collect::<Comments>();
+        let mut layouts = Layouts::default();
+        layouts.window(&long, 100., 110.);
+        // A cursor left by another pool is not used past the end of a chunk.
+        assert_eq!(layouts.window(&short, 3., 10.).len(), 2);
+
+        // Empty pools that comments are appended to are told apart.
+        let mut first = Comments::default();
+        first.append(long);
+        let mut second = Comments::default();
+        second.append(short);
+        layouts.start(&first);
+        layouts.window(&first, 100., 110.);
+        layouts.start(&second);
+        assert_eq!(layouts.cursors, []);
+        assert_eq!(
+            layouts
+                .window(&second, 0., 10.)
+                .iter()
+                .map(|entry| entry.message)
+                .collect::<Vec<_>>(),
+            ["b"; 5]
+        );
+    }
+}
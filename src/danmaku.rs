@@ -1,25 +1,311 @@
-use anyhow::{anyhow, Result};
-use hex::encode;
-use md5::{Digest, Md5};
-use reqwest::Client;
-use serde::Deserialize;
+use crate::{
+    cache::{file_hash, folder_anime, read_cache, set_folder_anime, write_cache},
+    comments::Comments,
+    error::{Error, Result},
+    http::{client, send_json},
+    log::log_error,
+    options::options,
+    parser::{parse, Release},
+};
+use reqwest::RequestBuilder;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use std::{
-    collections::HashMap,
-    fs::File,
-    io::{copy, Read},
-    path::Path,
+    borrow::Cow,
+    collections::HashSet,
+    fmt::{self, Display, Formatter},
+    fs::{canonicalize, metadata, read_dir},
+    io,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
 };
-use unicode_segmentation::UnicodeSegmentation;
+use tokio::task::{spawn_blocking, JoinSet};
 
-pub struct Danmaku {
-    pub message: String,
-    pub count: usize,
+/// A parsed comment, before it is added to [`Comments`]. The text is borrowed
+/// from the source where possible.
+pub struct Danmaku<'a> {
+    pub message: Cow<'a, str>,
     pub time: f64,
     pub r: u8,
     pub g: u8,
     pub b: u8,
-    pub x: Option<f64>,
-    pub row: Option<usize>,
+    pub source: Source,
+    pub mode: Mode,
+    pub effect: Option<Box<Positioned>>,
+    /// The font size relative to the configured one.
+    pub size: f64,
+}
+
+impl<'a> Danmaku<'a> {
+    pub fn new(
+        message: impl Into<Cow<'a, str>>,
+        time: f64,
+        mode: Mode,
+        size: f64,
+        color: u32,
+        source: Source,
+    ) -> Self {
+        Self {
+            message: message.into(),
+            time,
+            r: (color >> 16) as u8,
+            g: (color >> 8) as u8,
+            b: color as u8,
+            source,
+            mode,
+            effect: None,
+            size,
+        }
+    }
+
+    /// Creates a comment from its source fields, parsing the payload of
+    /// positioned (mode 7) comments. Returns `None` if the payload is malformed.
+    pub fn parse(
+        message: &'a str,
+        time: f64,
+        mode: u8,
+        size: f64,
+        color: u32,
+        source: Source,
+    ) -> Option<Self> {
+        if mode != 7 {
+            return Some(Self::new(message, time, mode.into(), size, color, source));
+        }
+        let (positioned, text) = Positioned::parse(message)?;
+        let mut danmaku = Self::new(text, time, Mode::Positioned, size, color, source);
+        danmaku.effect = Some(Box::new(positioned));
+        Some(danmaku)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    Scroll,
+    /// Scrolling from left to right.
+    Reverse,
+    Top,
+    Bottom,
+    /// Drawn with its own [`Positioned`] effect.
+    Positioned,
+}
+
+/// The effect of a Bilibili advanced comment. Positions are fractions of the
+/// screen size, and times are in seconds.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Positioned {
+    pub start: (f64, f64),
+    pub end: (f64, f64),
+    /// The opacity at the start and at the end.
+    pub alpha: (f64, f64),
+    pub duration: f64,
+    pub rotate_z: f64,
+    pub rotate_y: f64,
+    pub move_duration: f64,
+    pub move_delay: f64,
+    pub accelerate: bool,
+}
+
+impl Positioned {
+    /// The stage size of Bilibili's player, which absolute positions refer to.
+    const STAGE: (f64, f64) = (682., 438.);
+    /// The longest a comment stays on screen, in seconds. Every frame looks
+    /// back this far for comments still showing.
+    const LONGEST: f64 = 60.;
+
+    /// Parses the payload of a mode 7 comment: a JSON array of the start
+    /// position, opacity, duration, text, rotation, end position, motion
+    /// duration and delay, outline, font and easing. Returns the effect and the
+    /// text.
+    fn parse(payload: &str) -> Option<(Self, String)> {
+        let values = serde_json::from_str::<Vec<Value>>(payload).ok()?;
+        let number = |i: usize| {
+            match values.get(i)? {
+                Value::Number(n) => n.as_f64(),
+                Value::String(s) => s.trim().parse().ok(),
+                _ => None,
+            }
+            .filter(|n: &f64| n.is_finite())
+        };
+        // Positions with a decimal point are relative, others are in pixels.
+        let position = |i: usize, stage: f64| {
+            let relative = match values.get(i)? {
+                Value::Number(n) => n.is_f64(),
+                Value::String(s) => s.contains('.'),
+                _ => false,
+            };
+            number(i).map(|n| if relative { n } else { n / stage })
+        };
+
+        let start = (position(0, Self::STAGE.0)?, position(1, Self::STAGE.1)?);
+        let alpha = match values.get(2) {
+            Some(Value::String(s)) if s.contains('-') => {
+                let (from, to) = s.split_once('-')?;
+                (from.trim().parse().ok()?, to.trim().parse().ok()?)
+            }
+            Some(_) => (number(2)?, number(2)?),
+            None => (1., 1.),
+        };
+        let duration = number(3).filter(|&d| d > 0.)?.min(Self::LONGEST);
+        let text = values.get(4)?.as_str()?.replace("/n", "\n");
+        let positioned = Self {
+            start,
+            end: (
+                position(7, Self::STAGE.0).unwrap_or(start.0),
+                position(8, Self::STAGE.1).unwrap_or(start.1),
+            ),
+            alpha: (alpha.0.clamp(0., 1.), alpha.1.clamp(0., 1.)),
+            duration,
+            rotate_z: number(5).unwrap_or(0.),
+            rotate_y: number(6).unwrap_or(0.),
+            move_duration: number(9).map(|d| d / 1000.).unwrap_or(duration),
+            move_delay: number(10).map(|d| d / 1000.).unwrap_or(0.),
+            accelerate: number(13) == Some(1.),
+        };
+        Some((positioned, text))
+    }
+}
+
+impl From<u8> for Mode {
+    fn from(mode: u8) -> Self {
+        match mode {
+            4 => Self::Bottom,
+            5 => Self::Top,
+            6 => Self::Reverse,
+            _ => Self::Scroll,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Source {
+    Dandanplay,
+    Bilibili,
+    Acfun,
+    Gamer,
+    Tucao,
+    Iqiyi,
+    Tencent,
+    Youku,
+    Other,
+    Local,
+}
+
+impl Source {
+    fn from_url(url: &str) -> Self {
+        let host = url
+            .split_once("://")
+            .map(|(_, rest)| rest)
+            .unwrap_or(url)
+            .split(['/', '?'])
+            .next()
+            .unwrap_or_default();
+        [
+            ("bilibili.com", Self::Bilibili),
+            ("acfun.cn", Self::Acfun),
+            ("gamer.com.tw", Self::Gamer),
+            ("tucao.one", Self::Tucao),
+            ("iqiyi.com", Self::Iqiyi),
+            ("qq.com", Self::Tencent),
+            ("youku.com", Self::Youku),
+        ]
+        .into_iter()
+        .find(|(domain, _)| host == *domain || host.ends_with(&format!(".{}", domain)))
+        .map(|(_, source)| source)
+        .unwrap_or(Self::Other)
+    }
+}
+
+impl Display for Source {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::Dandanplay => "dandanplay",
+            Self::Bilibili => "bilibili",
+            Self::Acfun => "acfun",
+            Self::Gamer => "gamer",
+            Self::Tucao => "tucao",
+            Self::Iqiyi => "iqiyi",
+            Self::Tencent => "tencent",
+            Self::Youku => "youku",
+            Self::Other => "other",
+            Self::Local => "local",
+        })
+    }
+}
+
+impl FromStr for Source {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "dandanplay" => Ok(Self::Dandanplay),
+            "bilibili" => Ok(Self::Bilibili),
+            "acfun" => Ok(Self::Acfun),
+            "gamer" => Ok(Self::Gamer),
+            "tucao" => Ok(Self::Tucao),
+            "iqiyi" => Ok(Self::Iqiyi),
+            "tencent" => Ok(Self::Tencent),
+            "youku" => Ok(Self::Youku),
+            "other" => Ok(Self::Other),
+            "local" => Ok(Self::Local),
+            _ => Err(Error::InvalidOption(format!("unknown source: {}", s))),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum MatchMode {
+    HashAndFileName,
+    HashOnly,
+    FileNameOnly,
+}
+
+impl FromStr for MatchMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "hash_and_file_name" => Ok(Self::HashAndFileName),
+            "hash_only" => Ok(Self::HashOnly),
+            "file_name_only" => Ok(Self::FileNameOnly),
+            _ => Err(Error::InvalidOption(format!("invalid match mode: {}", s))),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum ChConvert {
+    None = 0,
+    Simplified = 1,
+    Traditional = 2,
+}
+
+impl FromStr for ChConvert {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "none" => Ok(Self::None),
+            "simplified" => Ok(Self::Simplified),
+            "traditional" => Ok(Self::Traditional),
+            _ => Err(Error::InvalidOption(format!("invalid conversion: {}", s))),
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct MatchRequest {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    file_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    file_hash: Option<String>,
+    file_size: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    video_duration: Option<u64>,
+    match_mode: MatchMode,
 }
 
 #[derive(Deserialize)]
@@ -29,10 +315,150 @@ struct MatchResponse {
     matches: Vec<Match>,
 }
 
+#[derive(Serialize)]
+struct BatchRequest<'a> {
+    requests: &'a [MatchRequest],
+}
+
 #[derive(Deserialize)]
-struct Match {
+struct BatchResponse {
+    results: Vec<BatchResult>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BatchResult {
+    success: bool,
+    file_hash: String,
+    match_result: Option<Match>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Match {
+    #[serde(rename = "episodeId")]
+    pub episode_id: usize,
+    #[serde(rename = "animeId")]
+    pub anime_id: usize,
+    #[serde(rename = "animeTitle")]
+    pub anime_title: String,
+    #[serde(rename = "episodeTitle")]
+    pub episode_title: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+}
+
+impl Match {
+    /// Whether the anime and titles are known, which they are not for an
+    /// episode chosen by id that was never matched.
+    pub fn is_known(&self) -> bool {
+        self.anime_id != 0 && !self.anime_title.is_empty()
+    }
+}
+
+#[derive(Deserialize)]
+struct SearchResponse {
+    animes: Vec<SearchAnime>,
+}
+
+#[derive(Deserialize)]
+struct SearchAnime {
+    #[serde(rename = "animeId")]
+    anime_id: usize,
+    #[serde(rename = "animeTitle")]
+    anime_title: String,
+    #[serde(rename = "type")]
+    kind: String,
+    episodes: Vec<SearchEpisode>,
+}
+
+#[derive(Deserialize)]
+struct SearchEpisode {
+    #[serde(rename = "episodeId")]
+    episode_id: usize,
+    #[serde(rename = "episodeTitle")]
+    episode_title: String,
+}
+
+#[derive(Deserialize)]
+struct BangumiResponse {
+    bangumi: Bangumi,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Bangumi {
+    #[serde(rename = "animeId")]
+    anime_id: usize,
+    #[serde(rename = "animeTitle")]
+    anime_title: String,
+    #[serde(rename = "type")]
+    kind: String,
+    episodes: Vec<BangumiEpisode>,
+}
+
+impl Bangumi {
+    fn episode(&self, index: usize) -> Option<Match> {
+        self.episodes.get(index).map(|episode| Match {
+            episode_id: episode.episode_id,
+            anime_id: self.anime_id,
+            anime_title: self.anime_title.clone(),
+            episode_title: episode.episode_title.clone(),
+            kind: self.kind.clone(),
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct CachedBangumi {
+    /// When the episode list was fetched, or 0 if it was cached before that was
+    /// recorded.
+    #[serde(default)]
+    time: u64,
+    #[serde(flatten)]
+    bangumi: Bangumi,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct BangumiEpisode {
     #[serde(rename = "episodeId")]
     episode_id: usize,
+    #[serde(rename = "episodeTitle")]
+    episode_title: String,
+}
+
+#[derive(Deserialize)]
+struct RelatedResponse {
+    relateds: Vec<Related>,
+}
+
+#[derive(Deserialize)]
+struct Related {
+    url: String,
+    shift: f64,
+}
+
+#[derive(Serialize)]
+struct SendRequest<'a> {
+    time: f64,
+    mode: u8,
+    color: u32,
+    comment: &'a str,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct HistoryRequest<'a> {
+    episode_id_list: &'a [usize],
+    add_to_favorite: bool,
+    rating: u8,
+}
+
+#[derive(Deserialize)]
+struct ApiResponse {
+    success: bool,
+    #[serde(rename = "errorMessage")]
+    error_message: String,
+    #[serde(default)]
+    cid: u64,
 }
 
 #[derive(Deserialize)]
@@ -40,66 +466,905 @@ struct CommentResponse {
     comments: Vec<Comment>,
 }
 
-#[derive(Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 struct Comment {
+    #[serde(default)]
+    cid: u64,
+    #[serde(default)]
     p: String,
-    m: String,
+    /// The text, which a malformed comment may lack.
+    #[serde(default)]
+    m: Option<String>,
 }
 
-pub async fn get_danmaku<P: AsRef<Path>>(path: P) -> Result<Vec<Danmaku>> {
-    let file = File::open(&path)?;
-    let mut hasher = Md5::new();
-    // https://api.dandanplay.net/swagger/ui/index
-    copy(&mut file.take(16 * 1024 * 1024), &mut hasher)?;
-    let hash = encode(hasher.finalize());
-    let file_name = path.as_ref().file_name().unwrap().to_str().unwrap();
-
-    let client = Client::new();
-    let data = client
-        .post("https://api.dandanplay.net/api/v2/match")
-        .header("Content-Type", "application/json")
-        .json(&HashMap::from([
-            ("fileName", file_name),
-            ("fileHash", &hash),
-        ]))
-        .send()
-        .await?
-        .json::<MatchResponse>()
-        .await?;
-    if data.matches.len() > 1 {
-        return Err(anyhow!("multiple matching episodes"));
-    } else if !data.is_matched {
-        return Err(anyhow!("no matching episode"));
+#[derive(Default)]
+pub struct Episode {
+    /// `None` if the file could not be matched and only the comments of its
+    /// sidecar file are loaded.
+    pub matched: Option<Match>,
+    pub comments: Comments,
+    pub skipped: usize,
+    pub sources: Vec<Source>,
+    pub failed: Vec<Origin>,
+    /// The pools of sources that were off when the episode was loaded.
+    pub missing: Vec<Origin>,
+    /// The ids of dandanplay's own comments that are loaded.
+    pub known: HashSet<u64>,
+}
+
+impl Episode {
+    pub fn episode_id(&self) -> Option<usize> {
+        self.matched.as_ref().map(|matched| matched.episode_id)
     }
+}
 
-    let mut danmaku = client
-        .get(format!(
-            "https://api.dandanplay.net/api/v2/comment/{}?withRelated=true",
-            data.matches[0].episode_id
-        ))
-        .send()
-        .await?
-        .json::<CommentResponse>()
-        .await?
-        .comments
+/// A comment pool to fetch: dandanplay's own, or a related third-party one.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct Origin {
+    pub source: Source,
+    url: Option<String>,
+    shift: f64,
+}
+
+impl Origin {
+    /// Dandanplay's own comment pool.
+    fn dandanplay() -> Self {
+        Self {
+            source: Source::Dandanplay,
+            url: None,
+            shift: 0.,
+        }
+    }
+
+    pub fn is_dandanplay(&self) -> bool {
+        *self == Self::dandanplay()
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Pool {
+    origin: Origin,
+    comments: Vec<Comment>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CachedEpisode {
+    time: u64,
+    origins: Vec<Origin>,
+    pools: Vec<Pool>,
+}
+
+pub enum Media {
+    File(PathBuf),
+    Stream { title: String },
+}
+
+impl Media {
+    pub fn new(path: String, title: Option<String>) -> Self {
+        let is_url = path.split_once("://").is_some_and(|(scheme, _)| {
+            scheme.len() > 1
+                && scheme
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c))
+        });
+        if is_url {
+            Self::Stream {
+                title: title.unwrap_or(path),
+            }
+        } else {
+            Self::File(path.into())
+        }
+    }
+}
+
+/// Matches the media to an episode, or looks up the given one. `foreground`
+/// is whether the media is being played, which shows retries on the OSD.
+pub async fn match_media(
+    media: Media,
+    duration: Option<f64>,
+    episode_id: Option<usize>,
+    foreground: bool,
+) -> Result<Match> {
+    let sibling = match &media {
+        Media::File(path) => canonical(path.clone()).await.ok().and_then(|path| {
+            let release = parse(path.file_name()?.to_str()?);
+            Some((path.parent()?.to_path_buf(), release))
+        }),
+        Media::Stream { .. } => None,
+    }
+    .filter(|(_, release)| !release.title.is_empty() && release.episode.is_some());
+    let matched = match episode_id {
+        Some(episode_id) => get_episode(episode_id).await,
+        None => match match_episode(media, duration, foreground).await {
+            Ok(matched) => matched,
+            // Fall back to an earlier match of a sibling file, as when offline.
+            Err(error) => {
+                let Some((dir, release)) = &sibling else {
+                    return Err(error);
+                };
+                match infer_episode(dir, release, foreground).await {
+                    Ok(Some(matched)) => {
+                        unsafe { log_error(error) };
+                        return Ok(matched);
+                    }
+                    Ok(None) => return Err(error),
+                    Err(inferred) => {
+                        unsafe { log_error(inferred) };
+                        return Err(error);
+                    }
+                }
+            }
+        },
+    };
+    if let (Some((dir, release)), false) = (&sibling, matched.anime_id == 0) {
+        if let Err(error) = remember_folder(dir, release, &matched, foreground).await {
+            unsafe { log_error(error) };
+        }
+    }
+    Ok(matched)
+}
+
+/// Loads the comments of the episode, fetching the pools of the sources that
+/// are not disabled. Cached pools of disabled sources are loaded too, so that
+/// turning a source on shows them right away.
+pub async fn get_danmaku(
+    matched: Match,
+    disabled: &HashSet<Source>,
+    foreground: bool,
+) -> Result<Episode> {
+    let episode_id = matched.episode_id;
+    let name = comments_cache(episode_id);
+    let cached = read_cache::<CachedEpisode>(name.clone()).await;
+    let fresh = cached
+        .as_ref()
+        .is_some_and(|cached| now() < cached.time + options().cache_ttl);
+    let mut related_error = None;
+    let (origins, time) = match &cached {
+        Some(cached) if fresh => (cached.origins.clone(), cached.time),
+        _ => match get_related(episode_id, foreground).await {
+            Ok(origins) => (origins, now()),
+            // Fall back to the cached comments when offline.
+            Err(error) => match &cached {
+                Some(cached) => {
+                    unsafe { log_error(error) };
+                    (cached.origins.clone(), cached.time)
+                }
+                // Dandanplay's own comments may still load. The related pools
+                // are looked up again next time, as the entry is stale.
+                None => {
+                    related_error = Some(error);
+                    (vec![Origin::dandanplay()], 0)
+                }
+            },
+        },
+    };
+    let mut sources = Vec::new();
+    for origin in &origins {
+        if !sources.contains(&origin.source) {
+            sources.push(origin.source);
+        }
+    }
+
+    let mut pools = cached.map(|cached| cached.pools).unwrap_or_default();
+    // Comments cached before their ids were kept cannot be told apart from new
+    // ones when refreshing, so the pool is fetched again.
+    pools.retain(|pool| {
+        !pool.origin.is_dandanplay() || pool.comments.iter().all(|comment| comment.cid != 0)
+    });
+    let outdated = origins
+        .iter()
+        .filter(|origin| !disabled.contains(&origin.source))
+        .filter(|origin| !fresh || !pools.iter().any(|pool| pool.origin == **origin))
+        .cloned()
+        .collect::<Vec<_>>();
+    let updated = !outdated.is_empty();
+    let (fetched, failed) = get_pools(episode_id, outdated, foreground).await;
+    pools.retain(|pool| !fetched.iter().any(|fetched| fetched.origin == pool.origin));
+    pools.extend(fetched);
+    // Stale pools are still better than none.
+    let failed = failed
         .into_iter()
-        .map(|comment| {
-            let mut p = comment.p.splitn(4, ',');
-            let t = p.next().unwrap().parse().unwrap();
-            _ = p.next().unwrap();
-            let c = p.next().unwrap().parse::<u32>().unwrap();
-            Danmaku {
-                message: comment.m.replace('\n', "\\N"),
-                count: comment.m.graphemes(true).count(),
-                time: t,
-                r: (c / (256 * 256)).try_into().unwrap(),
-                g: (c % (256 * 256) / 256).try_into().unwrap(),
-                b: (c % 256).try_into().unwrap(),
-                x: None,
-                row: None,
+        .filter(|origin| !pools.iter().any(|pool| pool.origin == *origin))
+        .collect();
+    if let Some(error) = related_error {
+        if pools.is_empty() {
+            return Err(error);
+        }
+        unsafe { log_error(error) };
+    }
+    let missing = origins
+        .iter()
+        .filter(|origin| disabled.contains(&origin.source))
+        .filter(|origin| !pools.iter().any(|pool| pool.origin == **origin))
+        .cloned()
+        .collect();
+
+    let (comments, skipped) = parse_pools(&pools);
+    let known = known_ids(&pools);
+    if updated {
+        write_cache(
+            name,
+            CachedEpisode {
+                time,
+                origins,
+                pools,
+            },
+        )
+        .await;
+    }
+    Ok(Episode {
+        matched: Some(matched),
+        comments,
+        skipped,
+        sources,
+        failed,
+        missing,
+        known,
+    })
+}
+
+/// Fetches the comment pools that failed to load, returning their comments
+/// and the number of malformed ones, together with the pools that failed
+/// again and the ids of dandanplay's own comments.
+pub async fn get_origins(
+    episode_id: usize,
+    origins: Vec<Origin>,
+) -> (Comments, usize, Vec<Origin>, HashSet<u64>) {
+    let (fetched, failed) = get_pools(episode_id, origins, true).await;
+    let (comments, skipped) = parse_pools(&fetched);
+    let known = known_ids(&fetched);
+    if !fetched.is_empty() {
+        let name = comments_cache(episode_id);
+        if let Some(mut cached) = read_cache::<CachedEpisode>(name.clone()).await {
+            cached
+                .pools
+                .retain(|pool| !fetched.iter().any(|fetched| fetched.origin == pool.origin));
+            cached.pools.extend(fetched);
+            write_cache(name, cached).await;
+        }
+    }
+    (comments, skipped, failed, known)
+}
+
+async fn get_related(episode_id: usize, foreground: bool) -> Result<Vec<Origin>> {
+    let related = send_json::<RelatedResponse>(
+        client().get(format!(
+            "https://api.dandanplay.net/api/v2/related/{}",
+            episode_id
+        )),
+        foreground,
+    )
+    .await?
+    .relateds;
+    let mut origins = vec![Origin::dandanplay()];
+    origins.extend(related.into_iter().map(|related| Origin {
+        source: Source::from_url(&related.url),
+        url: Some(related.url),
+        shift: related.shift,
+    }));
+    Ok(origins)
+}
+
+async fn get_pools(
+    episode_id: usize,
+    origins: Vec<Origin>,
+    foreground: bool,
+) -> (Vec<Pool>, Vec<Origin>) {
+    let mut tasks = JoinSet::new();
+    for origin in origins {
+        let request = comment_request(episode_id, &origin);
+        tasks.spawn(async move {
+            let result = get_comments(request, foreground).await;
+            (origin, result)
+        });
+    }
+
+    let mut pools = Vec::new();
+    let mut failed = Vec::new();
+    while let Some(result) = tasks.join_next().await {
+        match result {
+            Ok((origin, Ok(comments))) => pools.push(Pool { origin, comments }),
+            Ok((origin, Err(error))) => {
+                unsafe { log_error(error) };
+                failed.push(origin);
+            }
+            Err(error) => unsafe { log_error(io::Error::other(error).into()) },
+        }
+    }
+    (pools, failed)
+}
+
+/// The cache file of the comments of an episode. The server converts them as
+/// `ch_convert` asks, so each setting has its own file. Disabled sources need
+/// none, as their pools are left out of the file and fetched once enabled.
+fn comments_cache(episode_id: usize) -> String {
+    format!(
+        "comments/{}-{}.json",
+        episode_id,
+        options().ch_convert as u8
+    )
+}
+
+fn comment_request(episode_id: usize, origin: &Origin) -> RequestBuilder {
+    let ch_convert = (options().ch_convert as u8).to_string();
+    match &origin.url {
+        None => client()
+            .get(format!(
+                "https://api.dandanplay.net/api/v2/comment/{}",
+                episode_id
+            ))
+            .query(&[("withRelated", "false"), ("chConvert", &ch_convert)]),
+        Some(url) => client()
+            .get("https://api.dandanplay.net/api/v2/extcomment")
+            .query(&[("url", url), ("chConvert", &ch_convert)]),
+    }
+}
+
+/// Fetches dandanplay's own comments again, returning the ones whose ids are
+/// not `known` and their ids.
+pub async fn refresh_comments(
+    episode_id: usize,
+    known: HashSet<u64>,
+) -> Result<(Comments, HashSet<u64>)> {
+    let origin = Origin::dandanplay();
+    let comments = get_comments(comment_request(episode_id, &origin), false).await?;
+    let new = Pool {
+        origin,
+        comments: comments
+            .iter()
+            .filter(|comment| !known.contains(&comment.cid))
+            .cloned()
+            .collect(),
+    };
+    if !new.comments.is_empty() {
+        let name = comments_cache(episode_id);
+        if let Some(mut cached) = read_cache::<CachedEpisode>(name.clone()).await {
+            if let Some(pool) = cached
+                .pools
+                .iter_mut()
+                .find(|pool| pool.origin == new.origin)
+            {
+                pool.comments = comments;
+                write_cache(name, cached).await;
+            }
+        }
+    }
+    let ids = new.comments.iter().map(|comment| comment.cid).collect();
+    Ok((parse_pools(&[new]).0, ids))
+}
+
+/// Returns the ids of the comments in dandanplay's own pool.
+fn known_ids(pools: &[Pool]) -> HashSet<u64> {
+    pools
+        .iter()
+        .filter(|pool| pool.origin.is_dandanplay())
+        .flat_map(|pool| pool.comments.iter().map(|comment| comment.cid))
+        .collect()
+}
+
+/// Parses the comments of the given pools sorted by time, dropping and
+/// counting the malformed ones.
+fn parse_pools(pools: &[Pool]) -> (Comments, usize) {
+    let mut skipped = 0;
+    let comments = pools
+        .iter()
+        .flat_map(|pool| {
+            pool.comments
+                .iter()
+                .map(move |comment| parse_comment(comment, &pool.origin))
+        })
+        .filter_map(|danmaku| {
+            if danmaku.is_none() {
+                skipped += 1;
+            }
+            danmaku
+        })
+        .collect::<Comments>();
+    if skipped > 0 {
+        unsafe { log_error(Error::Malformed(skipped)) };
+    }
+    (comments, skipped)
+}
+
+/// Parses a comment whose `p` field is `time,mode,color,user`.
+fn parse_comment<'a>(comment: &'a Comment, origin: &Origin) -> Option<Danmaku<'a>> {
+    let mut p = comment.p.split(',');
+    let time = p.next()?.parse::<f64>().ok().filter(|t| t.is_finite())?;
+    let mode = p.next()?.parse::<u8>().ok()?;
+    let color = p.next()?.parse::<u32>().ok().filter(|&c| c <= 0xffffff)?;
+    Danmaku::parse(
+        comment.m.as_deref()?,
+        time + origin.shift,
+        mode,
+        1.,
+        color,
+        origin.source,
+    )
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Matches the given files and downloads their comments into the cache so that
+/// they load instantly, or without a connection, when played.
+pub async fn prefetch(paths: Vec<PathBuf>, disabled: HashSet<Source>) {
+    if options().match_mode != MatchMode::FileNameOnly {
+        if let Err(error) = batch_match(&paths).await {
+            unsafe { log_error(error) };
+        }
+    }
+    for path in paths {
+        let matched = match match_media(Media::File(path), None, None, false).await {
+            Ok(matched) => matched,
+            Err(error) => {
+                unsafe { log_error(error) };
+                continue;
+            }
+        };
+        if let Err(error) = get_danmaku(matched, &disabled, false).await {
+            unsafe { log_error(error) };
+        }
+    }
+}
+
+/// Matches the files whose hashes are not cached yet in as few requests as
+/// possible, caching every exact match.
+async fn batch_match(paths: &[PathBuf]) -> Result<()> {
+    let mut requests = Vec::new();
+    for path in paths {
+        let (request, _) = match file_request(path, None).await {
+            Ok(request) => request,
+            // The file is matched on its own when played.
+            Err(error) => {
+                unsafe { log_error(error) };
+                continue;
+            }
+        };
+        let hash = request.file_hash.clone().unwrap();
+        if read_cache::<Match>(format!("matches/{}.json", hash))
+            .await
+            .is_none()
+        {
+            requests.push(request);
+        }
+    }
+    // https://api.dandanplay.net/swagger/ui/index
+    for requests in requests.chunks(32) {
+        let data = send_json::<BatchResponse>(
+            client()
+                .post("https://api.dandanplay.net/api/v2/match/batch")
+                .json(&BatchRequest { requests }),
+            false,
+        )
+        .await?;
+        for result in data.results {
+            if let (true, Some(matched)) = (result.success, result.match_result) {
+                remember_episode(&matched).await;
+                write_cache(format!("matches/{}.json", result.file_hash), matched).await;
             }
+        }
+    }
+    Ok(())
+}
+
+/// Predicts the episode of a file that could not be matched from an earlier
+/// match of another file of the same title in the same directory.
+async fn infer_episode(dir: &Path, release: &Release, foreground: bool) -> Result<Option<Match>> {
+    let Some((anime_id, offset)) = (unsafe { folder_anime(dir, &release.title) }) else {
+        return Ok(None);
+    };
+    let bangumi = get_bangumi(anime_id, foreground).await?;
+    Ok(
+        usize::try_from(i64::from(release.episode.unwrap()) + offset)
+            .ok()
+            .and_then(|i| bangumi.episode(i)),
+    )
+}
+
+/// Looks up the metadata of a manually chosen episode from an earlier match.
+/// An episode that was never matched has an anime ID of 0 and empty titles.
+async fn get_episode(episode_id: usize) -> Match {
+    read_cache(format!("episodes/{}.json", episode_id))
+        .await
+        .unwrap_or_else(|| Match {
+            episode_id,
+            anime_id: 0,
+            anime_title: String::new(),
+            episode_title: String::new(),
+            kind: String::new(),
         })
+}
+
+/// Records the anime of a matched episode, which only match responses tell.
+async fn remember_episode(matched: &Match) {
+    write_cache(
+        format!("episodes/{}.json", matched.episode_id),
+        matched.clone(),
+    )
+    .await;
+}
+
+async fn remember_folder(
+    dir: &Path,
+    release: &Release,
+    matched: &Match,
+    foreground: bool,
+) -> Result<()> {
+    let episodes = get_bangumi(matched.anime_id, foreground).await?.episodes;
+    if let Some(index) = episodes
+        .iter()
+        .position(|episode| episode.episode_id == matched.episode_id)
+    {
+        let offset = index as i64 - i64::from(release.episode.unwrap());
+        unsafe { set_folder_anime(dir, &release.title, matched.anime_id, offset)? };
+    }
+    Ok(())
+}
+
+/// Returns every episode of the given anime, recording their anime so that
+/// they can be chosen with `danmaku-episode` later.
+pub async fn get_season(anime_id: usize) -> Result<Vec<Match>> {
+    let bangumi = get_bangumi(anime_id, false).await?;
+    let episodes = (0..bangumi.episodes.len())
+        .filter_map(|index| bangumi.episode(index))
         .collect::<Vec<_>>();
-    danmaku.sort_by(|a, b| a.time.partial_cmp(&b.time).unwrap());
-    Ok(danmaku)
+    for matched in &episodes {
+        remember_episode(matched).await;
+    }
+    Ok(episodes)
+}
+
+/// Records the episodes of the files in the directory of the given one that
+/// have the same title and extension, from the episode number of the file
+/// matched last, so that they are matched without a connection.
+pub async fn match_season_files(path: PathBuf, episodes: &[Match]) -> Result<()> {
+    if options().match_mode == MatchMode::FileNameOnly {
+        return Ok(());
+    }
+    let path = canonical(path).await?;
+    let (Some(dir), Some(name)) = (path.parent(), path.file_name().and_then(|n| n.to_str())) else {
+        return Ok(());
+    };
+    let title = parse(name).title;
+    let Some((anime_id, offset)) = (unsafe { folder_anime(dir, &title) }) else {
+        return Ok(());
+    };
+    if episodes.first().map(|matched| matched.anime_id) != Some(anime_id) {
+        return Ok(());
+    }
+    let dir = dir.to_path_buf();
+    let extension = path.extension().map(|extension| extension.to_os_string());
+    let files = spawn_blocking(move || {
+        read_dir(dir)?
+            .map(|entry| Ok(entry?.path()))
+            .collect::<io::Result<Vec<_>>>()
+    })
+    .await
+    .map_err(io::Error::other)??;
+    for file in files {
+        if file.extension().map(|extension| extension.to_os_string()) != extension {
+            continue;
+        }
+        let Some(release) = file.file_name().and_then(|n| n.to_str()).map(parse) else {
+            continue;
+        };
+        let Some(matched) = release
+            .episode
+            .filter(|_| release.title == title)
+            .and_then(|episode| usize::try_from(i64::from(episode) + offset).ok())
+            .and_then(|index| episodes.get(index))
+        else {
+            continue;
+        };
+        match file_hash(file).await {
+            Ok((hash, _)) => write_cache(format!("matches/{}.json", hash), matched.clone()).await,
+            Err(error) => unsafe { log_error(error.into()) },
+        }
+    }
+    Ok(())
+}
+
+/// Resolves the path off the runtime, as the file may be on a slow network
+/// share.
+async fn canonical(path: PathBuf) -> io::Result<PathBuf> {
+    spawn_blocking(move || canonicalize(path))
+        .await
+        .map_err(io::Error::other)?
+}
+
+/// Returns the episode list of the anime, from the cache while it is younger
+/// than `cache_ttl`.
+async fn get_bangumi(anime_id: usize, foreground: bool) -> Result<Bangumi> {
+    let name = format!("bangumi/{}.json", anime_id);
+    let cached = match read_cache::<CachedBangumi>(name.clone()).await {
+        Some(cached) if now() < cached.time + options().cache_ttl => return Ok(cached.bangumi),
+        cached => cached,
+    };
+    match fetch_bangumi(anime_id, foreground).await {
+        Ok(bangumi) => {
+            write_cache(
+                name,
+                CachedBangumi {
+                    time: now(),
+                    bangumi: bangumi.clone(),
+                },
+            )
+            .await;
+            Ok(bangumi)
+        }
+        // Fall back to the cached episode list when offline.
+        Err(error) => match cached {
+            Some(cached) => {
+                unsafe { log_error(error) };
+                Ok(cached.bangumi)
+            }
+            None => Err(error),
+        },
+    }
+}
+
+async fn fetch_bangumi(anime_id: usize, foreground: bool) -> Result<Bangumi> {
+    Ok(send_json::<BangumiResponse>(
+        client().get(format!(
+            "https://api.dandanplay.net/api/v2/bangumi/{}",
+            anime_id
+        )),
+        foreground,
+    )
+    .await?
+    .bangumi)
+}
+
+async fn file_request(
+    path: &Path,
+    duration: Option<f64>,
+) -> Result<(MatchRequest, Option<Release>)> {
+    let match_mode = options().match_mode;
+    let release = (match_mode != MatchMode::HashOnly)
+        .then(|| parse(path.file_name().unwrap().to_str().unwrap()));
+    let (file_hash, file_size) = if match_mode == MatchMode::FileNameOnly {
+        let path = path.to_path_buf();
+        let metadata = spawn_blocking(move || metadata(path))
+            .await
+            .map_err(io::Error::other)??;
+        (None, metadata.len())
+    } else {
+        let (hash, size) = file_hash(path.to_path_buf()).await?;
+        (Some(hash), size)
+    };
+    let request = MatchRequest {
+        // The raw name is sent if nothing is left of it once parsed.
+        file_name: release.as_ref().map(|release| {
+            release
+                .name()
+                .unwrap_or_else(|| path.file_stem().unwrap().to_string_lossy().into_owned())
+        }),
+        file_hash,
+        file_size,
+        video_duration: duration.map(|d| d as u64),
+        match_mode,
+    };
+    Ok((request, release))
+}
+
+async fn match_episode(media: Media, duration: Option<f64>, foreground: bool) -> Result<Match> {
+    let (request, release) = match &media {
+        Media::File(path) => file_request(path, duration).await?,
+        Media::Stream { title } => {
+            let release = parse(title);
+            let request = MatchRequest {
+                file_name: Some(release.name().unwrap_or_else(|| title.clone())),
+                file_hash: None,
+                file_size: 0,
+                video_duration: duration.map(|d| d as u64),
+                match_mode: MatchMode::FileNameOnly,
+            };
+            (request, Some(release))
+        }
+    };
+    let Some(hash) = request.file_hash.clone() else {
+        let matched = request_match(request, release, foreground).await?;
+        remember_episode(&matched).await;
+        return Ok(matched);
+    };
+    if let Some(matched) = read_cache(format!("matches/{}.json", hash)).await {
+        return Ok(matched);
+    }
+    let matched = request_match(request, release, foreground).await?;
+    write_cache(format!("matches/{}.json", hash), matched.clone()).await;
+    remember_episode(&matched).await;
+    Ok(matched)
+}
+
+async fn request_match(
+    request: MatchRequest,
+    release: Option<Release>,
+    foreground: bool,
+) -> Result<Match> {
+    let data = send_json::<MatchResponse>(
+        client()
+            .post("https://api.dandanplay.net/api/v2/match")
+            .header("Content-Type", "application/json")
+            .json(&request),
+        foreground,
+    )
+    .await?;
+    if data.matches.len() > 1 {
+        return Err(Error::AmbiguousMatch);
+    } else if data.is_matched {
+        return Ok(data.matches.into_iter().next().unwrap());
+    }
+
+    let Some(release) = release.filter(|release| !release.title.is_empty()) else {
+        return Err(Error::NoMatch);
+    };
+    let mut query = vec![("anime", release.title)];
+    if let Some(episode) = release.episode {
+        query.push(("episode", episode.to_string()));
+    }
+    let mut matches = send_json::<SearchResponse>(
+        client()
+            .get("https://api.dandanplay.net/api/v2/search/episodes")
+            .query(&query),
+        foreground,
+    )
+    .await?
+    .animes
+    .into_iter()
+    .flat_map(|anime| {
+        anime.episodes.into_iter().map(move |episode| Match {
+            episode_id: episode.episode_id,
+            anime_id: anime.anime_id,
+            anime_title: anime.anime_title.clone(),
+            episode_title: episode.episode_title,
+            kind: anime.kind.clone(),
+        })
+    })
+    .collect::<Vec<_>>();
+    match matches.len() {
+        0 => Err(Error::NoMatch),
+        1 => Ok(matches.pop().unwrap()),
+        _ => Err(Error::AmbiguousMatch),
+    }
+}
+
+async fn get_comments(request: RequestBuilder, foreground: bool) -> Result<Vec<Comment>> {
+    Ok(send_json::<CommentResponse>(request, foreground)
+        .await?
+        .comments)
+}
+
+pub async fn report_history(episode_id: usize) -> Result<()> {
+    let Some(token) = &options().token else {
+        return Err(Error::NoToken);
+    };
+    let data = send_json::<ApiResponse>(
+        client()
+            .post("https://api.dandanplay.net/api/v2/playhistory")
+            .bearer_auth(token)
+            .json(&HistoryRequest {
+                episode_id_list: &[episode_id],
+                add_to_favorite: false,
+                rating: 0,
+            }),
+        false,
+    )
+    .await?;
+    if !data.success {
+        return Err(Error::Api(data.error_message));
+    }
+    Ok(())
+}
+
+pub async fn send_danmaku(
+    episode_id: usize,
+    time: f64,
+    mode: u8,
+    color: u32,
+    message: String,
+) -> Result<(Danmaku<'static>, u64)> {
+    let Some(token) = &options().token else {
+        return Err(Error::NoToken);
+    };
+    let data = send_json::<ApiResponse>(
+        client()
+            .post(format!(
+                "https://api.dandanplay.net/api/v2/comment/{}",
+                episode_id
+            ))
+            .bearer_auth(token)
+            .json(&SendRequest {
+                time,
+                mode,
+                color,
+                comment: &message,
+            }),
+        true,
+    )
+    .await?;
+    if !data.success {
+        return Err(Error::Api(data.error_message));
+    }
+
+    // Record the comment so that refreshing does not add it a second time.
+    let name = comments_cache(episode_id);
+    if let Some(mut cached) = read_cache::<CachedEpisode>(name.clone()).await {
+        if let Some(pool) = cached
+            .pools
+            .iter_mut()
+            .find(|pool| pool.origin.url.is_none())
+        {
+            pool.comments.push(Comment {
+                cid: data.cid,
+                p: format!("{:.2},{},{},0", time, mode, color),
+                m: Some(message.clone()),
+            });
+            write_cache(name, cached).await;
+        }
+    }
+    Ok((
+        Danmaku::new(message, time, mode.into(), 1., color, Source::Dandanplay),
+        data.cid,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MALFORMED: &str = r#"{"count": 9, "comments": [
+        {"cid": 1, "p": "1.50,1,16777215,user", "m": "ok"},
+        {"cid": 2, "p": "", "m": "empty"},
+        {"cid": 3, "p": "2.00", "m": "no mode"},
+        {"cid": 4, "p": "abc,1,255,user", "m": "bad time"},
+        {"cid": 5, "p": "NaN,1,255,user", "m": "nan time"},
+        {"cid": 6, "p": "3.00,x,255,user", "m": "bad mode"},
+        {"cid": 7, "p": "4.00,1,-1,user", "m": "negative color"},
+        {"cid": 8, "p": "5.00,1,16777216,user", "m": "color overflow"},
+        {"cid": 9, "m": "no p"},
+        {"cid": 10, "p": "0.50,5,255,user"}
+    ]}"#;
+
+    fn pool(source: Source, shift: f64, json: &str) -> Pool {
+        Pool {
+            origin: Origin {
+                source,
+                url: None,
+                shift,
+            },
+            comments: serde_json::from_str::<CommentResponse>(json)
+                .unwrap()
+                .comments,
+        }
+    }
+
+    #[test]
+    fn malformed() {
+        let (comments, skipped) = parse_pools(&[pool(Source::Dandanplay, 0., MALFORMED)]);
+        let danmaku = comments.iter().collect::<Vec<_>>();
+        assert_eq!(skipped, 9);
+        assert_eq!(danmaku.len(), 1);
+        assert_eq!(danmaku[0].message, "ok");
+        assert_eq!((danmaku[0].r, danmaku[0].g, danmaku[0].b), (255, 255, 255));
+    }
+
+    #[test]
+    fn malformed_related() {
+        let (comments, skipped) = parse_pools(&[
+            pool(Source::Dandanplay, 0., MALFORMED),
+            pool(Source::Bilibili, 10., MALFORMED),
+        ]);
+        assert_eq!(skipped, 18);
+        assert_eq!(
+            comments
+                .iter()
+                .map(|danmaku| (danmaku.time, danmaku.source))
+                .collect::<Vec<_>>(),
+            [(1.5, Source::Dandanplay), (11.5, Source::Bilibili)]
+        );
+    }
 }
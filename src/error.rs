@@ -0,0 +1,122 @@
+use crate::options::options;
+use std::{
+    fmt::{self, Display, Formatter},
+    io,
+    str::FromStr,
+};
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Network(reqwest::Error),
+    NoMatch,
+    AmbiguousMatch,
+    Parse(String),
+    Api(String),
+    NoToken,
+    /// A cache file that could not be read or written as JSON.
+    Cache(serde_json::Error),
+    /// An option value that is not one of the accepted ones.
+    InvalidOption(String),
+    /// Comments that were dropped because their fields are malformed.
+    Malformed(usize),
+    /// An error returned by the mpv client API.
+    Mpv(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Language {
+    English,
+    Chinese,
+}
+
+impl FromStr for Language {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "en" => Ok(Self::English),
+            "zh" => Ok(Self::Chinese),
+            _ => Err(Error::InvalidOption(format!("unknown language: {}", s))),
+        }
+    }
+}
+
+/// Picks the text in the configured language.
+pub fn localize<T>(english: T, chinese: T) -> T {
+    match options().language {
+        Language::English => english,
+        Language::Chinese => chinese,
+    }
+}
+
+impl Error {
+    /// The message shown on the OSD, in the configured language.
+    pub fn message(&self) -> String {
+        match options().language {
+            Language::English => self.to_string(),
+            Language::Chinese => match self {
+                Self::Io(error) => format!("读取文件失败：{}", error),
+                Self::Network(error) => format!("网络错误：{}", error),
+                Self::NoMatch => "没有匹配的剧集".into(),
+                Self::AmbiguousMatch => "匹配到多个剧集".into(),
+                Self::Parse(error) => format!("无法解析服务器响应：{}", error),
+                Self::Api(error) => format!("服务器错误：{}", error),
+                Self::NoToken => "未设置 token".into(),
+                Self::Cache(error) => format!("缓存文件错误：{}", error),
+                Self::InvalidOption(error) => format!("无效的选项：{}", error),
+                Self::Malformed(n) => format!("跳过了 {} 条格式错误的弹幕", n),
+                Self::Mpv(error) => format!("mpv 错误：{}", error),
+            },
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::Io(error) => write!(f, "failed to read file: {}", error),
+            Self::Network(error) => write!(f, "network error: {}", error),
+            Self::NoMatch => write!(f, "no matching episode"),
+            Self::AmbiguousMatch => write!(f, "multiple matching episodes"),
+            Self::Parse(error) => write!(f, "invalid server response: {}", error),
+            Self::Api(error) => write!(f, "server error: {}", error),
+            Self::NoToken => write!(f, "no token configured"),
+            Self::Cache(error) => write!(f, "invalid cache file: {}", error),
+            Self::InvalidOption(error) => write!(f, "invalid option: {}", error),
+            Self::Malformed(n) => write!(
+                f,
+                "skipped {} malformed comment{}",
+                n,
+                if *n > 1 { "s" } else { "" }
+            ),
+            Self::Mpv(error) => write!(f, "mpv error: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Self::Io(error)
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(error: reqwest::Error) -> Self {
+        if error.is_decode() {
+            Self::Parse(error.to_string())
+        } else {
+            Self::Network(error)
+        }
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(error: serde_json::Error) -> Self {
+        Self::Cache(error)
+    }
+}
@@ -0,0 +1,90 @@
+use crate::{
+    error::{localize, Result},
+    log::log_error,
+    options::options,
+    osd_message,
+};
+use reqwest::{Client, Method, Proxy, RequestBuilder, Response};
+use serde::de::DeserializeOwned;
+use std::{sync::OnceLock, time::Duration};
+use tokio::time::sleep;
+
+static CLIENT: OnceLock<Client> = OnceLock::new();
+
+pub fn client() -> &'static Client {
+    CLIENT.get_or_init(|| {
+        build_client().unwrap_or_else(|error| {
+            unsafe { log_error(error) };
+            Client::new()
+        })
+    })
+}
+
+fn build_client() -> Result<Client> {
+    let options = options();
+    let mut builder = Client::builder()
+        .connect_timeout(options.connect_timeout)
+        .read_timeout(options.read_timeout)
+        .user_agent(&options.user_agent);
+    if let Some(proxy) = &options.proxy {
+        builder = builder.proxy(Proxy::all(proxy)?);
+    }
+    Ok(builder.build()?)
+}
+
+/// Sends the request and parses the JSON response, retrying with exponential
+/// backoff on network errors, including while the body is read, and server
+/// errors. Requests other than `GET` are only retried if they could not
+/// connect, as the server may already have acted on them. Retries are shown on
+/// the OSD if the request is for the file being played.
+pub async fn send_json<T: DeserializeOwned>(
+    request: RequestBuilder,
+    foreground: bool,
+) -> Result<T> {
+    let retries = options().retries;
+    let idempotent = request
+        .try_clone()
+        .and_then(|request| request.build().ok())
+        .is_some_and(|request| request.method() == Method::GET);
+    let mut delay = Duration::from_secs(1);
+    let mut attempt = 0;
+    loop {
+        let Some(retry) = request.try_clone() else {
+            let body = request.send().await?.error_for_status()?.bytes().await?;
+            return Ok(serde_json::from_slice(&body)?);
+        };
+        // The JSON is parsed once the body is read, so errors here are all from
+        // the connection or the server.
+        let body = match retry.send().await.and_then(Response::error_for_status) {
+            Ok(response) => response.bytes().await,
+            Err(error) => Err(error),
+        };
+        match body {
+            Ok(body) => return Ok(serde_json::from_slice(&body)?),
+            Err(error)
+                if attempt < retries
+                    && (error.is_connect()
+                        || idempotent
+                            && (error.is_timeout()
+                                || error.is_request()
+                                || error.is_body()
+                                || error.is_decode()
+                                || error.status().is_some_and(|s| s.is_server_error()))) =>
+            {
+                attempt += 1;
+                unsafe {
+                    log_error(error.into());
+                    if foreground {
+                        osd_message(&localize(
+                            format!("Danmaku: retrying ({}/{})", attempt, retries),
+                            format!("弹幕：正在重试（{}/{}）", attempt, retries),
+                        ));
+                    }
+                }
+                sleep(delay).await;
+                delay *= 2;
+            }
+            Err(error) => return Err(error.into()),
+        }
+    }
+}
@@ -1,55 +1,64 @@
 #![allow(clippy::missing_safety_doc)]
 
+pub mod bilibili;
+pub mod cache;
+pub mod comments;
 pub mod danmaku;
+pub mod error;
 pub mod ffi;
+pub mod http;
 pub mod log;
 pub mod options;
 pub mod overlay;
+pub mod parser;
 pub mod property;
 
 use crate::{
-    danmaku::{get_danmaku, Danmaku},
+    bilibili::read_sidecar,
+    cache::evict_cache,
+    comments::{Comments, Entry, Layout, Layouts},
+    danmaku::{
+        get_danmaku, get_origins, get_season, match_media, match_season_files, prefetch,
+        refresh_comments, report_history, send_danmaku, Episode, Match, Media, Mode, Origin,
+        Positioned, Source,
+    },
+    error::{localize, Error, Result},
     ffi::{
         mpv_client_name, mpv_command, mpv_event_client_message, mpv_event_id, mpv_format,
         mpv_handle, mpv_observe_property, mpv_wait_event,
     },
     log::{log_code, log_error},
-    options::read_options,
+    options::{init_options, options},
     overlay::{osd_overlay, remove_overlay},
     property::{get_property_bool, get_property_f64, get_property_string},
 };
 use std::{
     cmp::max,
+    collections::HashSet,
     ffi::{CStr, CString},
     os::raw::c_int,
+    path::PathBuf,
     ptr::{null, null_mut},
     slice::from_raw_parts,
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc,
     },
+    time::Duration,
 };
-use tokio::{runtime::Builder, spawn, sync::Mutex};
+use tokio::{runtime::Builder, spawn, sync::Mutex, task::JoinHandle, time::sleep};
 
 const DURATION: f64 = 12.;
 const INTERVAL: f64 = 0.005;
 
 pub static mut CTX: *mut mpv_handle = null_mut();
 pub static mut CLIENT_NAME: &str = "";
-pub static mut FONT_SIZE: f64 = 40.;
 
 #[no_mangle]
 unsafe extern "C" fn mpv_open_cplugin(ctx: *mut mpv_handle) -> c_int {
     CTX = ctx;
     CLIENT_NAME = CStr::from_ptr(mpv_client_name(ctx)).to_str().unwrap();
-    read_options()
-        .map_err(|e| log_error(e))
-        .ok()
-        .flatten()
-        .unwrap_or_default()
-        .get("font_size")
-        .and_then(|s| s.parse().ok().filter(|&s| s > 0.))
-        .inspect(|&s| FONT_SIZE = s);
+    init_options();
 
     Builder::new_multi_thread()
         .enable_all()
@@ -65,9 +74,22 @@ async unsafe fn main(ctx: *mut mpv_handle) -> c_int {
         return -1;
     }
 
+    if let Some(expiry) = options().cache_expiry {
+        spawn(evict_cache(expiry));
+    }
+
     let comments = Arc::new(Mutex::new(None));
     let enabled = Arc::new(AtomicBool::new(false));
+    let disabled = Arc::new(Mutex::new(options().disabled_sources.clone()));
+    let matched = Arc::new(Mutex::new(None));
+    let mut layouts = Layouts::default();
+    let mut episode_id = None;
     let mut handle = spawn(async {});
+    let mut history = spawn(async {});
+    let mut refresh = spawn(async {});
+    let mut season = spawn(async {});
+    // Kept apart from `handle`, as it outlives the load that starts it.
+    let prefetching = Arc::new(Mutex::new(spawn(async {})));
     loop {
         let timeout = if enabled.load(Ordering::SeqCst)
             && matches!(get_property_bool(c"pause"), Some(false))
@@ -80,154 +102,950 @@ async unsafe fn main(ctx: *mut mpv_handle) -> c_int {
         match event.event_id {
             mpv_event_id::MPV_EVENT_SHUTDOWN => {
                 handle.abort();
+                history.abort();
+                refresh.abort();
+                season.abort();
+                prefetching.lock().await.abort();
                 return 0;
             }
             mpv_event_id::MPV_EVENT_FILE_LOADED => {
                 handle.abort();
+                history.abort();
+                refresh.abort();
+                prefetching.lock().await.abort();
+                layouts.reset();
+                episode_id = None;
+                *matched.lock().await = None;
                 *comments.lock().await = None;
-                if enabled.load(Ordering::SeqCst) {
-                    remove_overlay();
-                    handle = spawn(get(comments.clone(), enabled.clone()));
+                if options().token.is_some() {
+                    history = spawn(report(matched.clone(), episode_id));
+                }
+                if let Some(interval) = options().refresh_interval {
+                    refresh = spawn(update(comments.clone(), disabled.clone(), interval));
                 }
-            }
-            mpv_event_id::MPV_EVENT_SEEK => {
                 if enabled.load(Ordering::SeqCst) {
-                    if let Some(comments) = &mut *comments.lock().await {
-                        reset(comments);
-                    }
+                    remove_overlay();
+                    handle = spawn(get(
+                        comments.clone(),
+                        enabled.clone(),
+                        disabled.clone(),
+                        matched.clone(),
+                        prefetching.clone(),
+                        episode_id,
+                    ));
                 }
             }
+            mpv_event_id::MPV_EVENT_SEEK => layouts.reset(),
             mpv_event_id::MPV_EVENT_CLIENT_MESSAGE => {
                 let data = &*(event.data as *mut mpv_event_client_message);
-                if !from_raw_parts(data.args, data.num_args.try_into().unwrap())
-                    .first()
-                    .map(|&arg| CStr::from_ptr(arg) == c"toggle-danmaku")
-                    .unwrap_or_default()
-                {
-                    continue;
-                }
-                if enabled.fetch_xor(true, Ordering::SeqCst) {
-                    remove_overlay();
-                    osd_message("Danmaku: off");
-                } else {
-                    match &mut *comments.lock().await {
-                        Some(comments) => {
-                            reset(comments);
-                            loaded(comments.len());
+                let args = from_raw_parts(data.args, data.num_args.try_into().unwrap())
+                    .iter()
+                    .map(|&arg| CStr::from_ptr(arg).to_str().unwrap_or_default())
+                    .collect::<Vec<_>>();
+                match args.as_slice() {
+                    ["toggle-danmaku"] => {
+                        if enabled.fetch_xor(true, Ordering::SeqCst) {
+                            remove_overlay();
+                            osd_message(localize("Danmaku: off", "弹幕：关"));
+                        } else {
+                            layouts.reset();
+                            match &*comments.lock().await {
+                                Some(episode) => loaded(episode),
+                                None => {
+                                    osd_message(localize("Danmaku: on", "弹幕：开"));
+                                    handle.abort();
+                                    handle = spawn(get(
+                                        comments.clone(),
+                                        enabled.clone(),
+                                        disabled.clone(),
+                                        matched.clone(),
+                                        prefetching.clone(),
+                                        episode_id,
+                                    ));
+                                }
+                            }
                         }
-                        None => {
-                            osd_message("Danmaku: on");
-                            handle.abort();
-                            handle = spawn(get(comments.clone(), enabled.clone()));
+                    }
+                    ["toggle-danmaku-source", source] => {
+                        let Ok(source) = source.parse::<Source>() else {
+                            osd_message(&localize(
+                                format!("Danmaku: unknown source {}", source),
+                                format!("弹幕：未知来源 {}", source),
+                            ));
+                            continue;
+                        };
+                        let mut disabled_sources = disabled.lock().await;
+                        let off = !disabled_sources.remove(&source);
+                        if off {
+                            disabled_sources.insert(source);
+                        }
+                        drop(disabled_sources);
+                        osd_message(&localize(
+                            format!(
+                                "Danmaku source {}: {}",
+                                source,
+                                if off { "off" } else { "on" }
+                            ),
+                            format!("弹幕来源 {}：{}", source, if off { "关" } else { "开" }),
+                        ));
+                        // The loaded comments of the source are hidden or shown
+                        // from the next frame on.
+                        if !off {
+                            spawn(enable_source(comments.clone(), source));
+                        }
+                    }
+                    ["danmaku-episode", id] => {
+                        let Ok(id) = id.parse() else {
+                            osd_message(&localize(
+                                format!("Danmaku: invalid episode {}", id),
+                                format!("弹幕：无效的剧集 {}", id),
+                            ));
+                            continue;
+                        };
+                        episode_id = Some(id);
+                        osd_message(&localize(
+                            format!("Danmaku: episode {}", id),
+                            format!("弹幕：剧集 {}", id),
+                        ));
+                        *matched.lock().await = None;
+                        if options().token.is_some() {
+                            history.abort();
+                            history = spawn(report(matched.clone(), episode_id));
+                        }
+
+                        handle.abort();
+                        *comments.lock().await = None;
+                        if enabled.load(Ordering::SeqCst) {
+                            remove_overlay();
+                            handle = spawn(get(
+                                comments.clone(),
+                                enabled.clone(),
+                                disabled.clone(),
+                                matched.clone(),
+                                prefetching.clone(),
+                                episode_id,
+                            ));
+                        }
+                    }
+                    ["danmaku-send", message, rest @ ..] if rest.len() <= 2 => {
+                        let color = match rest.first() {
+                            Some(color) => match parse_color(color) {
+                                Some(color) => color,
+                                None => {
+                                    osd_message(&localize(
+                                        format!("Danmaku: invalid color {}", color),
+                                        format!("弹幕：无效的颜色 {}", color),
+                                    ));
+                                    continue;
+                                }
+                            },
+                            None => 0xffffff,
+                        };
+                        let mode = match rest.get(1) {
+                            Some(mode) => match mode.parse().ok().filter(|m| [1, 4, 5].contains(m))
+                            {
+                                Some(mode) => mode,
+                                None => {
+                                    osd_message(&localize(
+                                        format!("Danmaku: invalid mode {}", mode),
+                                        format!("弹幕：无效的模式 {}", mode),
+                                    ));
+                                    continue;
+                                }
+                            },
+                            None => 1,
+                        };
+                        let Some(episode_id) =
+                            comments.lock().await.as_ref().and_then(Episode::episode_id)
+                        else {
+                            osd_message(localize("Danmaku: no episode loaded", "弹幕：未加载剧集"));
+                            continue;
+                        };
+                        let Some(time) = get_property_f64(c"time-pos") else {
+                            continue;
+                        };
+                        spawn(send(
+                            comments.clone(),
+                            episode_id,
+                            time,
+                            mode,
+                            color,
+                            message.to_string(),
+                        ));
+                    }
+                    ["danmaku-retry"] => {
+                        if !enabled.load(Ordering::SeqCst) {
+                            osd_message(localize("Danmaku: off", "弹幕：关"));
+                            continue;
+                        }
+                        // An episode that was not matched is loaded again.
+                        let failed = comments.lock().await.as_ref().and_then(|episode| {
+                            episode.matched.as_ref().map(|_| !episode.failed.is_empty())
+                        });
+                        match failed {
+                            Some(true) => {
+                                osd_message(localize("Danmaku: retrying", "弹幕：正在重试"));
+                                spawn(retry(comments.clone()));
+                            }
+                            Some(false) => osd_message(localize(
+                                "Danmaku: nothing to retry",
+                                "弹幕：没有需要重试的内容",
+                            )),
+                            None if !handle.is_finished() => {
+                                osd_message(localize("Danmaku: loading", "弹幕：正在加载"))
+                            }
+                            None => {
+                                osd_message(localize("Danmaku: retrying", "弹幕：正在重试"));
+                                handle = spawn(get(
+                                    comments.clone(),
+                                    enabled.clone(),
+                                    disabled.clone(),
+                                    matched.clone(),
+                                    prefetching.clone(),
+                                    episode_id,
+                                ));
+                            }
+                        }
+                    }
+                    ["danmaku-prefetch-season"] => {
+                        if !season.is_finished() {
+                            osd_message(localize("Danmaku: already downloading", "弹幕：正在下载"));
+                            continue;
+                        }
+                        match &*matched.lock().await {
+                            // The anime of a manually chosen episode that was
+                            // never matched is unknown.
+                            Some(matched) if matched.anime_id != 0 => {
+                                season = spawn(download(matched.anime_id, disabled.clone()))
+                            }
+                            _ => osd_message(localize(
+                                "Danmaku: no episode matched",
+                                "弹幕：未匹配剧集",
+                            )),
                         }
                     }
+                    ["danmaku-sources"] => match &*comments.lock().await {
+                        Some(episode) => osd_message(&format!(
+                            "{}\n{}",
+                            localize("Danmaku sources:", "弹幕来源："),
+                            sources(episode, &*disabled.lock().await)
+                        )),
+                        None => {
+                            osd_message(localize("Danmaku: no sources loaded", "弹幕：未加载来源"))
+                        }
+                    },
+                    ["danmaku-info"] => match &*comments.lock().await {
+                        Some(episode) => {
+                            let text = match &episode.matched {
+                                Some(matched) if matched.is_known() => format!(
+                                    "{}{} ({})\n{}{}\n{}{} ({})\n",
+                                    localize("Anime: ", "番剧："),
+                                    matched.anime_title,
+                                    matched.anime_id,
+                                    localize("Type: ", "类型："),
+                                    matched.kind,
+                                    localize("Episode: ", "剧集："),
+                                    matched.episode_title,
+                                    matched.episode_id,
+                                ),
+                                // Only the id of an episode chosen by id is known.
+                                Some(matched) => format!(
+                                    "{}{}\n",
+                                    localize("Episode: ", "剧集："),
+                                    matched.episode_id
+                                ),
+                                None => String::new(),
+                            };
+                            osd_message(&format!(
+                                "{}{}{}\n{}",
+                                text,
+                                localize("Comments: ", "弹幕数："),
+                                episode.comments.len(),
+                                sources(episode, &*disabled.lock().await)
+                            ))
+                        }
+                        None => {
+                            osd_message(localize("Danmaku: no episode loaded", "弹幕：未加载剧集"))
+                        }
+                    },
+                    _ => continue,
                 }
             }
             _ => (),
         }
 
         if enabled.load(Ordering::SeqCst) {
-            if let Some(comments) = &mut *comments.lock().await {
-                render(comments);
+            // Render without holding the lock, so that merges are not held up.
+            let pool = comments
+                .lock()
+                .await
+                .as_ref()
+                .map(|episode| episode.comments.clone());
+            if let Some(pool) = pool {
+                let disabled = disabled.lock().await.clone();
+                render(&pool, &disabled, &mut layouts);
             }
         }
     }
 }
 
-unsafe fn render(comments: &mut Vec<Danmaku>) -> Option<()> {
+unsafe fn render(
+    comments: &Comments,
+    disabled: &HashSet<Source>,
+    layouts: &mut Layouts,
+) -> Option<()> {
     let width = get_property_f64(c"osd-width").filter(|&w| w > 0.)?;
     let height = get_property_f64(c"osd-height").filter(|&h| h > 0.)?;
     let pos = get_property_f64(c"time-pos")?;
-    let speed = get_property_f64(c"speed")?;
-    let spacing = FONT_SIZE / 10.;
-    let mut ends = Vec::new();
-    ends.resize(max((height / (FONT_SIZE + spacing)) as usize, 1), None);
+    let font_size = options().font_size;
+    let fixed_duration = options().fixed_duration;
+    let spacing = font_size / 10.;
+    let rows = max((height / (font_size + spacing)) as usize, 1);
+    // The direction of the comments scrolling through each row, and how far
+    // the trailing edge of the last one is from the edge they enter from.
+    let mut lanes = vec![None; rows];
+    // Rows held by top and bottom comments, which scrolling comments avoid.
+    let mut fixed = vec![false; rows];
 
-    let mut danmaku = Vec::new();
-    for comment in comments {
-        if comment.time > pos + DURATION / 2. {
-            break;
+    // How long ago the earliest comment still on screen may have appeared.
+    let lifetime = (DURATION * (1. + (comments.widest() * font_size + spacing) / width))
+        .max(fixed_duration)
+        .max(comments.longest());
+
+    layouts.start(comments);
+    let window = layouts
+        .window(comments, pos - lifetime, pos + DURATION / 2.)
+        .into_iter()
+        .filter(|comment| !disabled.contains(&comment.source))
+        .collect::<Vec<_>>();
+    // The font size, length and number of rows of a comment.
+    let measure = |comment: &Entry| {
+        let size = comment.size * font_size;
+        let span = ((comment.lines as f64 * size + spacing) / (font_size + spacing)).ceil();
+        (
+            size,
+            comment.count as f64 * size,
+            (span as usize).clamp(1, rows),
+        )
+    };
+    // The direction and position of a scrolling comment, and how far its
+    // leading edge is from the edge it entered from. Derived from the time on
+    // every frame, so that comments never drift from their timestamps however
+    // late a frame is.
+    let scroll = |comment: &Entry, length: f64| {
+        let reverse = (comment.mode == Mode::Reverse) != options().reverse;
+        let distance = (pos - comment.time) * width / DURATION;
+        let x = if reverse {
+            distance - length
+        } else {
+            width - distance
+        };
+        let lead = if reverse { x + length } else { width - x };
+        (reverse, x, lead)
+    };
+    let scrolling = |comment: &&Entry| {
+        comment.effect.is_none() && matches!(comment.mode, Mode::Scroll | Mode::Reverse)
+    };
+
+    // Fixed comments keep clear of the rows of scrolling comments already on
+    // screen where they can.
+    for comment in window.iter().filter(scrolling) {
+        if let Some(layout) = layouts.get(comment.id) {
+            let (_, length, span) = measure(comment);
+            let (reverse, _, lead) = scroll(comment, length);
+            let row = (layout.row as usize).min(rows - span);
+            occupy(
+                &mut lanes[row..row + span],
+                reverse,
+                lead - length - spacing,
+            );
         }
+    }
 
-        let x = comment
-            .x
-            .get_or_insert_with(|| width - (pos - comment.time) * width / DURATION);
-        if *x + comment.count as f64 * FONT_SIZE + spacing < 0. {
+    // Fixed rows are marked before any scrolling comment is laid out, so that
+    // none is placed on a row that a later fixed comment takes.
+    let mut overlay = Vec::new();
+    for comment in window.iter().filter(|comment| !scrolling(comment)) {
+        let (size, length, span) = measure(comment);
+        if let Some(effect) = comment.effect {
+            let elapsed = pos - comment.time;
+            if (0. ..=effect.duration).contains(&elapsed) {
+                overlay.push(positioned(comment, effect, elapsed, width, height, size));
+            }
+            continue;
+        }
+        if comment.time > pos || comment.time + fixed_duration < pos {
             continue;
         }
-        let row = *comment.row.get_or_insert_with(|| {
-            ends.iter()
-                .enumerate()
-                .find(|(_, end)| end.map(|end: f64| end < *x).unwrap_or(true))
-                .map(|(row, _)| row)
-                .unwrap_or_else(|| {
-                    ends.iter()
-                        .enumerate()
-                        .min_by(|a, b| a.1.partial_cmp(b.1).unwrap())
-                        .map(|(row, _)| row)
-                        .unwrap()
-                })
+        let far = (width + length) / 2.;
+        let layout = layouts.get(comment.id).unwrap_or_else(|| {
+            let row = if comment.mode == Mode::Top {
+                find_rows(0..=rows - span, span, &lanes, &fixed, None, far)
+            } else {
+                find_rows((0..=rows - span).rev(), span, &lanes, &fixed, None, far)
+            };
+            Layout { row: row as u16 }
         });
-        danmaku.push(format!(
-            "{{\\pos({},{})\\c&H{:x}{:x}{:x}&\\alpha&H30\\fs{}\\bord1.5\\b1\\q2}}{}",
-            *x,
-            row as f64 * (FONT_SIZE + spacing),
-            comment.b,
-            comment.g,
-            comment.r,
-            FONT_SIZE,
+        layouts.set(comment.id, layout);
+        let row = (layout.row as usize).min(rows - span);
+        fixed[row..row + span].fill(true);
+        overlay.push(format!(
+            "{{\\an8\\pos({},{}){}}}{}",
+            width / 2.,
+            row as f64 * (font_size + spacing),
+            style(comment, size),
             comment.message
         ));
+    }
 
-        *x -= width / DURATION * speed * INTERVAL;
-        if let Some(end) = ends.get_mut(row) {
-            let new_end = *x + comment.count as f64 * FONT_SIZE + spacing;
-            match end {
-                Some(end) => *end = end.max(new_end),
-                None => *end = Some(new_end),
-            }
+    let taken = |row: usize, span: usize| {
+        let row = row.min(rows - span);
+        fixed[row..row + span].contains(&true)
+    };
+    lanes.fill(None);
+    let mut danmaku = Vec::new();
+    for comment in window.iter().filter(scrolling) {
+        let (size, length, span) = measure(comment);
+        let (reverse, x, lead) = scroll(comment, length);
+        if lead - length - spacing > width {
+            continue;
         }
+        let row = match layouts.get(comment.id) {
+            // A comment moves off a row that a fixed comment has taken since.
+            Some(layout) if !taken(layout.row.into(), span) => layout.row.into(),
+            _ => find_rows(0..=rows - span, span, &lanes, &fixed, Some(reverse), lead),
+        };
+        danmaku.push(format!(
+            "{{\\pos({},{}){}}}{}",
+            x,
+            row.min(rows - span) as f64 * (font_size + spacing),
+            style(comment, size),
+            comment.message
+        ));
+
+        layouts.set(comment.id, Layout { row: row as u16 });
+        let row = row.min(rows - span);
+        occupy(
+            &mut lanes[row..row + span],
+            reverse,
+            lead - length - spacing,
+        );
     }
+    // Fixed and positioned comments are drawn over the scrolling ones.
+    danmaku.append(&mut overlay);
+    layouts.finish();
     osd_overlay(&danmaku.join("\n"), width as i64, height as i64);
     Some(())
 }
 
-async unsafe fn get(comments: Arc<Mutex<Option<Vec<Danmaku>>>>, enabled: Arc<AtomicBool>) {
-    let Some(path) = get_property_string(c"path") else {
+/// Records a comment scrolling in the given direction through the rows, whose
+/// trailing edge is `gap` from the edge it entered from.
+fn occupy(lanes: &mut [Option<(bool, f64)>], reverse: bool, gap: f64) {
+    for lane in lanes {
+        *lane = match *lane {
+            Some((direction, end)) if direction == reverse => Some((reverse, gap.min(end))),
+            _ => Some((reverse, gap)),
+        };
+    }
+}
+
+/// Returns the first of the given rows where `span` rows are free of fixed
+/// comments, of comments scrolling the other way, and of comments that have
+/// not yet scrolled past `lead`, or failing that the free one whose comments
+/// have scrolled the farthest.
+fn find_rows(
+    rows: impl Iterator<Item = usize> + Clone,
+    span: usize,
+    lanes: &[Option<(bool, f64)>],
+    fixed: &[bool],
+    reverse: Option<bool>,
+    lead: f64,
+) -> usize {
+    let first = rows.clone().next().unwrap_or(0);
+    let free = rows.filter(|&row| !fixed[row..row + span].contains(&true));
+    let gap = |row: usize| {
+        lanes[row..row + span]
+            .iter()
+            .flatten()
+            .map(|&(_, gap)| gap)
+            .fold(f64::INFINITY, f64::min)
+    };
+    let aligned = |row: usize| {
+        lanes[row..row + span]
+            .iter()
+            .flatten()
+            .all(|&(direction, _)| reverse.map(|reverse| reverse == direction).unwrap_or(true))
+    };
+    free.clone()
+        .find(|&row| aligned(row) && gap(row) > lead)
+        .or_else(|| free.max_by(|&a, &b| gap(a).total_cmp(&gap(b))))
+        .unwrap_or(first)
+}
+
+/// Renders a positioned comment `elapsed` seconds after it appeared.
+fn positioned(
+    comment: &Entry,
+    effect: &Positioned,
+    elapsed: f64,
+    width: f64,
+    height: f64,
+    size: f64,
+) -> String {
+    // The overlay is redrawn every frame, so times are given relative to now.
+    let ms = |time: f64| ((time - elapsed) * 1000.).round() as i64;
+    let (x1, y1) = (effect.start.0 * width, effect.start.1 * height);
+    let (x2, y2) = (effect.end.0 * width, effect.end.1 * height);
+    // The position is computed here rather than with `\move`, whose times
+    // fall back to the whole event once both have passed.
+    let k = if effect.move_duration > 0. {
+        ((elapsed - effect.move_delay) / effect.move_duration).clamp(0., 1.)
+    } else if elapsed < effect.move_delay {
+        0.
+    } else {
+        1.
+    };
+    let k = if effect.accelerate { k * k } else { k };
+    let motion = format!("\\pos({},{})", x1 + (x2 - x1) * k, y1 + (y2 - y1) * k);
+    // `\fad` can't express this: it fades between fully transparent and the
+    // comment's alpha, while these fade between two arbitrary alphas, and its
+    // fade out is timed from the end of the event, which the overlay lacks.
+    let alpha = |opacity: f64| ((1. - opacity) * 255.).round() as u8;
+    format!(
+        "{{\\an7{}{}\\frz{}\\fry{}\\alpha&H{:02X}&\\t({},{},\\alpha&H{:02X}&)}}{}",
+        motion,
+        style(comment, size),
+        -effect.rotate_z,
+        effect.rotate_y,
+        alpha(effect.alpha.0),
+        ms(0.),
+        ms(effect.duration),
+        alpha(effect.alpha.1),
+        comment.message
+    )
+}
+
+fn style(comment: &Entry, size: f64) -> String {
+    format!(
+        "\\c&H{:x}{:x}{:x}&\\alpha&H30\\fs{}\\bord1.5\\b1\\q2",
+        comment.b, comment.g, comment.r, size
+    )
+}
+
+async unsafe fn get(
+    comments: Arc<Mutex<Option<Episode>>>,
+    enabled: Arc<AtomicBool>,
+    disabled: Arc<Mutex<HashSet<Source>>>,
+    matched: Arc<Mutex<Option<Match>>>,
+    prefetching: Arc<Mutex<JoinHandle<()>>>,
+    episode_id: Option<usize>,
+) {
+    load(comments, enabled, disabled.clone(), matched, episode_id).await;
+    let disabled = disabled.lock().await.clone();
+    // Prefetching is not part of loading, which `danmaku-retry` waits for.
+    let mut prefetching = prefetching.lock().await;
+    prefetching.abort();
+    *prefetching = spawn(prefetch(upcoming(), disabled));
+}
+
+async unsafe fn load(
+    comments: Arc<Mutex<Option<Episode>>>,
+    enabled: Arc<AtomicBool>,
+    disabled: Arc<Mutex<HashSet<Source>>>,
+    matched: Arc<Mutex<Option<Match>>>,
+    episode_id: Option<usize>,
+) {
+    let previous = matched.lock().await.clone();
+    let episode_match = match previous {
+        Some(episode_match) => Ok(episode_match),
+        None => match match_file(episode_id, true).await {
+            Some(Ok(episode_match)) => {
+                *matched.lock().await = Some(episode_match.clone());
+                Ok(episode_match)
+            }
+            Some(Err(error)) => Err(error),
+            None => return,
+        },
+    };
+
+    let disabled = disabled.lock().await.clone();
+    let result = match episode_match {
+        Ok(episode_match) => get_danmaku(episode_match, &disabled, true).await,
+        Err(error) => Err(error),
+    };
+    let sidecar = sidecar().await;
+    let mut episode = match result {
+        Ok(episode) => episode,
+        // The comments of a sidecar file load without a match or a connection.
+        Err(error) if sidecar.is_some() => {
+            log_error(error);
+            Episode::default()
+        }
+        Err(error) => {
+            show_error(&enabled, error);
+            return;
+        }
+    };
+    if let Some((danmaku, skipped)) = sidecar {
+        episode.comments.append(danmaku);
+        episode.skipped += skipped;
+        episode.sources.push(Source::Local);
+    }
+    if enabled.load(Ordering::SeqCst) {
+        if let Some(true) = get_property_bool(c"pause") {
+            render(&episode.comments, &disabled, &mut Layouts::default());
+        }
+        loaded(&episode);
+    }
+    *comments.lock().await = Some(episode)
+}
+
+/// Matches the file being played, or looks up the given episode. Returns
+/// `None` if no file is loaded.
+async unsafe fn match_file(episode_id: Option<usize>, foreground: bool) -> Option<Result<Match>> {
+    let path = get_property_string(c"path")?;
+    let title = get_property_string(c"media-title").or_else(|| get_property_string(c"filename"));
+    let duration = get_property_f64(c"duration");
+    Some(match_media(Media::new(path, title), duration, episode_id, foreground).await)
+}
+
+/// Reads the comments of a Bilibili or niconico XML file next to the video.
+async unsafe fn sidecar() -> Option<(Comments, usize)> {
+    let Some(Media::File(path)) = get_property_string(c"path").map(|path| Media::new(path, None))
+    else {
+        return None;
+    };
+    read_sidecar(path).await.unwrap_or_else(|error| {
+        log_error(error);
+        None
+    })
+}
+
+/// Returns the local files among the next `prefetch` playlist entries.
+unsafe fn upcoming() -> Vec<PathBuf> {
+    let (Some(pos), Some(count)) = (
+        get_property_f64(c"playlist-pos"),
+        get_property_f64(c"playlist-count"),
+    ) else {
+        return Vec::new();
+    };
+    let start = pos as usize + 1;
+    let end = (count as usize).min(start + options().prefetch);
+    (start..end)
+        .filter_map(|i| {
+            let name = CString::new(format!("playlist/{}/filename", i)).unwrap();
+            match Media::new(get_property_string(&name)?, None) {
+                Media::File(path) => Some(path),
+                Media::Stream { .. } => None,
+            }
+        })
+        .collect()
+}
+
+/// Fetches the comment pools that failed to load and merges them into the
+/// loaded ones.
+async unsafe fn retry(comments: Arc<Mutex<Option<Episode>>>) {
+    let Some((episode_id, failed)) = comments
+        .lock()
+        .await
+        .as_ref()
+        .and_then(|episode| Some((episode.episode_id()?, episode.failed.clone())))
+    else {
         return;
     };
-    match get_danmaku(path).await {
-        Ok(mut danmaku) => {
-            if enabled.load(Ordering::SeqCst) {
-                if let Some(true) = get_property_bool(c"pause") {
-                    render(&mut danmaku);
+    let (danmaku, skipped, failed, known) = get_origins(episode_id, failed).await;
+    if let Some(episode) = &mut *comments.lock().await {
+        if episode.episode_id() == Some(episode_id) {
+            episode.comments.append(danmaku);
+            episode.skipped += skipped;
+            episode.failed = failed;
+            episode.known.extend(known);
+            loaded(episode);
+        }
+    }
+}
+
+/// Fetches the pools of a source that was off when the episode was loaded and
+/// merges them into the loaded ones.
+async unsafe fn enable_source(comments: Arc<Mutex<Option<Episode>>>, source: Source) {
+    let Some((episode_id, origins)) = comments.lock().await.as_ref().and_then(|episode| {
+        Some((
+            episode.episode_id()?,
+            episode
+                .missing
+                .iter()
+                .filter(|origin| origin.source == source)
+                .cloned()
+                .collect::<Vec<_>>(),
+        ))
+    }) else {
+        return;
+    };
+    if origins.is_empty() {
+        return;
+    }
+    let (danmaku, skipped, failed, known) = get_origins(episode_id, origins.clone()).await;
+    if let Some(episode) = &mut *comments.lock().await {
+        if episode.episode_id() == Some(episode_id) {
+            episode.comments.append(danmaku);
+            episode.skipped += skipped;
+            episode.known.extend(known);
+            episode.missing.retain(|origin| !origins.contains(origin));
+            episode.failed.extend(failed);
+        }
+    }
+}
+
+unsafe fn show_error(enabled: &AtomicBool, error: Error) {
+    if enabled.load(Ordering::SeqCst) {
+        osd_message(&format!(
+            "{}{}",
+            localize("Danmaku: ", "弹幕："),
+            error.message()
+        ));
+    }
+    log_error(error);
+}
+
+async unsafe fn send(
+    comments: Arc<Mutex<Option<Episode>>>,
+    episode_id: usize,
+    time: f64,
+    mode: u8,
+    color: u32,
+    message: String,
+) {
+    match send_danmaku(episode_id, time, mode, color, message).await {
+        Ok((danmaku, cid)) => {
+            if let Some(episode) = &mut *comments.lock().await {
+                if episode.episode_id() == Some(episode_id) {
+                    episode.comments.insert(danmaku);
+                    episode.known.insert(cid);
                 }
-                loaded(danmaku.len());
             }
-            *comments.lock().await = Some(danmaku)
+            osd_message(localize("Danmaku: sent", "弹幕：已发送"));
         }
         Err(error) => {
-            if enabled.load(Ordering::SeqCst) {
-                osd_message(&format!("Danmaku: {}", error));
-            }
+            osd_message(&format!(
+                "{}{}",
+                localize("Danmaku: ", "弹幕："),
+                error.message()
+            ));
             log_error(error);
         }
     }
 }
 
-fn reset(comments: &mut Vec<Danmaku>) {
-    for comment in comments {
-        comment.x = None;
-        comment.row = None;
+/// Downloads the comments of every episode of the anime into the cache.
+async unsafe fn download(anime_id: usize, disabled: Arc<Mutex<HashSet<Source>>>) {
+    let episodes = match get_season(anime_id).await {
+        Ok(episodes) => episodes,
+        Err(error) => {
+            osd_message(&format!(
+                "{}{}",
+                localize("Danmaku: ", "弹幕："),
+                error.message()
+            ));
+            log_error(error);
+            return;
+        }
+    };
+    if let Some(Media::File(path)) = get_property_string(c"path").map(|path| Media::new(path, None))
+    {
+        if let Err(error) = match_season_files(path, &episodes).await {
+            log_error(error);
+        }
+    }
+    let disabled = disabled.lock().await.clone();
+    let mut failed = 0;
+    for (i, matched) in episodes.iter().enumerate() {
+        osd_message(&format!(
+            "{} {}/{}\n{}",
+            localize("Danmaku: downloading", "弹幕：正在下载"),
+            i + 1,
+            episodes.len(),
+            matched.episode_title
+        ));
+        match get_danmaku(matched.clone(), &disabled, false).await {
+            Ok(episode) if episode.failed.is_empty() => (),
+            Ok(_) => failed += 1,
+            Err(error) => {
+                log_error(error);
+                failed += 1;
+            }
+        }
+    }
+    let n = episodes.len() - failed;
+    if failed == 0 {
+        osd_message(&localize(
+            format!(
+                "Danmaku: downloaded {} episode{}",
+                n,
+                if n > 1 { "s" } else { "" }
+            ),
+            format!("弹幕：已下载 {} 集", n),
+        ));
+    } else {
+        osd_message(&localize(
+            format!(
+                "Danmaku: downloaded {} episode{}, {} failed",
+                n,
+                if n > 1 { "s" } else { "" },
+                failed
+            ),
+            format!("弹幕：已下载 {} 集，{} 集失败", n, failed),
+        ));
     }
 }
 
-unsafe fn loaded(n: usize) {
-    osd_message(&format!(
-        "Loaded {} danmaku comment{}",
-        n,
-        if n > 1 { "s" } else { "" }
-    ));
+/// Periodically merges newly posted dandanplay comments into the loaded ones,
+/// leaving the layout of the comments on screen untouched.
+async unsafe fn update(
+    comments: Arc<Mutex<Option<Episode>>>,
+    disabled: Arc<Mutex<HashSet<Source>>>,
+    interval: Duration,
+) {
+    loop {
+        sleep(interval).await;
+        if disabled.lock().await.contains(&Source::Dandanplay) {
+            continue;
+        }
+        // Only new comments are merged into a loaded pool, which is told apart
+        // by the ids of the comments in it.
+        let Some((episode_id, known)) = comments
+            .lock()
+            .await
+            .as_ref()
+            .filter(|episode| {
+                !episode
+                    .failed
+                    .iter()
+                    .chain(&episode.missing)
+                    .any(Origin::is_dandanplay)
+            })
+            .and_then(|episode| Some((episode.episode_id()?, episode.known.clone())))
+        else {
+            continue;
+        };
+        let (danmaku, ids) = match refresh_comments(episode_id, known).await {
+            Ok(refreshed) => refreshed,
+            Err(error) => {
+                log_error(error);
+                continue;
+            }
+        };
+        if let Some(episode) = &mut *comments.lock().await {
+            if episode.episode_id() == Some(episode_id) {
+                episode.comments.append(danmaku);
+                episode.known.extend(ids);
+            }
+        }
+    }
+}
+
+/// Reports the episode to the play history once playback passes
+/// `history_threshold` percent of the duration.
+async unsafe fn report(matched: Arc<Mutex<Option<Match>>>, episode_id: Option<usize>) {
+    loop {
+        sleep(Duration::from_secs(5)).await;
+        // Playback has ended, or the stream has no duration to measure against.
+        let (Some(pos), Some(duration)) = (
+            get_property_f64(c"time-pos"),
+            get_property_f64(c"duration").filter(|&d| d > 0.),
+        ) else {
+            return;
+        };
+        if pos / duration * 100. >= options().history_threshold {
+            break;
+        }
+    }
+    let previous = matched.lock().await.clone();
+    let episode_id = match previous {
+        Some(matched) => matched.episode_id,
+        // Danmaku is off, or has not matched the file yet.
+        None => match match_file(episode_id, false).await {
+            Some(Ok(matched)) => matched.episode_id,
+            Some(Err(error)) => {
+                log_error(error);
+                return;
+            }
+            None => return,
+        },
+    };
+
+    let mut delay = Duration::from_secs(5);
+    for _ in 0..5 {
+        match report_history(episode_id).await {
+            Ok(()) => return,
+            Err(error) => log_error(error),
+        }
+        sleep(delay).await;
+        delay *= 2;
+    }
+}
+
+fn parse_color(color: &str) -> Option<u32> {
+    match color.strip_prefix('#').or_else(|| color.strip_prefix("0x")) {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => color.parse().ok(),
+    }
+    .filter(|&color| color <= 0xffffff)
+}
+
+unsafe fn loaded(episode: &Episode) {
+    let n = episode.comments.len();
+    let mut text = localize(
+        format!(
+            "Loaded {} danmaku comment{}",
+            n,
+            if n > 1 { "s" } else { "" }
+        ),
+        format!("已加载 {} 条弹幕", n),
+    );
+    if episode.skipped > 0 {
+        text.push_str(&localize(
+            format!(", skipped {} malformed", episode.skipped),
+            format!("，跳过 {} 条格式错误的弹幕", episode.skipped),
+        ));
+    }
+    if let Some(matched) = episode.matched.as_ref().filter(|m| m.is_known()) {
+        text.push_str(&format!(
+            "\n{} {} ({})",
+            matched.anime_title, matched.episode_title, matched.kind,
+        ));
+    }
+    if !episode.failed.is_empty() {
+        let failed = episode
+            .failed
+            .iter()
+            .map(|origin| origin.source.to_string())
+            .collect::<Vec<_>>();
+        text.push_str(&format!(
+            "\n{}{}",
+            localize("Failed to load: ", "加载失败："),
+            failed.join(", ")
+        ));
+    }
+    osd_message(&text);
+}
+
+fn sources(episode: &Episode, disabled: &HashSet<Source>) -> String {
+    episode
+        .sources
+        .iter()
+        .map(|source| {
+            if disabled.contains(source) {
+                format!("{}: {}", source, localize("off", "关"))
+            } else {
+                let n = episode
+                    .comments
+                    .iter()
+                    .filter(|comment| comment.source == *source)
+                    .count();
+                format!("{}: {}", source, n)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
 }
 
 unsafe fn osd_message(text: &str) {
@@ -238,3 +1056,56 @@ unsafe fn osd_message(text: &str) {
         log_code(error);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn motion() {
+        let effect = Positioned {
+            start: (0., 0.),
+            end: (0.5, 1.),
+            alpha: (1., 1.),
+            duration: 10.,
+            rotate_z: 0.,
+            rotate_y: 0.,
+            move_duration: 2.,
+            move_delay: 1.,
+            accelerate: false,
+        };
+        let comment = Entry {
+            id: 0,
+            message: "a",
+            count: 1,
+            lines: 1,
+            time: 0.,
+            r: 0,
+            g: 0,
+            b: 0,
+            source: Source::Bilibili,
+            mode: Mode::Positioned,
+            effect: Some(&effect),
+            size: 1.,
+        };
+        let position = |effect: &Positioned, elapsed| {
+            let text = positioned(&comment, effect, elapsed, 100., 100., 25.);
+            text[text.find("\\pos").unwrap()..text.find(')').unwrap() + 1].to_owned()
+        };
+        assert_eq!(position(&effect, 0.5), "\\pos(0,0)");
+        assert_eq!(position(&effect, 2.), "\\pos(25,50)");
+        // The comment stays at the end once the motion is over.
+        assert_eq!(position(&effect, 5.), "\\pos(50,100)");
+        let effect = Positioned {
+            move_duration: 0.,
+            ..effect
+        };
+        assert_eq!(position(&effect, 5.), "\\pos(50,100)");
+        let effect = Positioned {
+            accelerate: true,
+            move_duration: 2.,
+            ..effect
+        };
+        assert_eq!(position(&effect, 2.), "\\pos(12.5,25)");
+    }
+}
@@ -1,14 +1,15 @@
-use crate::{ffi::mpv_error_string, CLIENT_NAME};
-use anyhow::Error;
+use crate::{error::Error, ffi::mpv_error_string, CLIENT_NAME};
 use std::ffi::{c_int, CStr};
 
 pub unsafe fn log_code(error: c_int) {
+    let client_name = CLIENT_NAME;
     eprintln!(
-        "[{CLIENT_NAME}] {}",
+        "[{client_name}] {}",
         CStr::from_ptr(mpv_error_string(error)).to_str().unwrap()
     );
 }
 
 pub unsafe fn log_error(error: Error) {
-    eprintln!("[{CLIENT_NAME}] {error}");
+    let client_name = CLIENT_NAME;
+    eprintln!("[{client_name}] {error}");
 }
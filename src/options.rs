@@ -1,37 +1,185 @@
 use crate::{
+    danmaku::{ChConvert, MatchMode, Source},
+    error::{Error, Language, Result},
     ffi::{mpv_command_ret, mpv_error_string, mpv_format, mpv_free_node_contents, mpv_node},
+    log::log_error,
     CLIENT_NAME, CTX,
 };
-use anyhow::{anyhow, Result};
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     ffi::{CStr, CString},
     fs::File,
-    io::{BufRead, BufReader, ErrorKind},
+    io::{self, BufRead, BufReader, ErrorKind},
     mem::MaybeUninit,
     ptr::{addr_of_mut, null},
+    sync::OnceLock,
+    time::Duration,
 };
 
-#[allow(clippy::uninit_assumed_init)]
-#[allow(invalid_value)]
-pub unsafe fn read_options() -> Result<Option<HashMap<String, String>>> {
-    let arg2 = CString::new(format!("~~/script-opts/{}.conf", CLIENT_NAME)).unwrap();
-    let mut args = [c"expand-path".as_ptr(), arg2.as_ptr(), null()];
-    let mut result = MaybeUninit::<mpv_node>::uninit().assume_init();
-    let error = mpv_command_ret(CTX, args.as_mut_ptr(), addr_of_mut!(result));
-    if error < 0 {
-        return Err(anyhow!(
-            "{}",
-            CStr::from_ptr(mpv_error_string(error)).to_str().unwrap()
-        ));
+pub struct Options {
+    pub font_size: f64,
+    pub fixed_duration: f64,
+    pub reverse: bool,
+    pub match_mode: MatchMode,
+    pub ch_convert: ChConvert,
+    pub disabled_sources: HashSet<Source>,
+    pub token: Option<String>,
+    pub history_threshold: f64,
+    pub connect_timeout: Duration,
+    pub read_timeout: Duration,
+    pub retries: u32,
+    pub proxy: Option<String>,
+    pub user_agent: String,
+    pub language: Language,
+    pub cache_ttl: u64,
+    pub cache_expiry: Option<Duration>,
+    pub prefetch: usize,
+    pub refresh_interval: Option<Duration>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            font_size: 40.,
+            fixed_duration: 5.,
+            reverse: false,
+            match_mode: MatchMode::HashAndFileName,
+            ch_convert: ChConvert::None,
+            disabled_sources: HashSet::new(),
+            token: None,
+            history_threshold: 80.,
+            connect_timeout: Duration::from_secs(10),
+            read_timeout: Duration::from_secs(30),
+            retries: 3,
+            proxy: None,
+            user_agent: concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION")).into(),
+            language: Language::English,
+            cache_ttl: 3600,
+            cache_expiry: Some(Duration::from_secs(30 * 24 * 3600)),
+            prefetch: 1,
+            refresh_interval: None,
+        }
     }
-    assert_eq!(result.format, mpv_format::MPV_FORMAT_STRING);
-    let path = CStr::from_ptr(result.u.string)
-        .to_str()
-        .unwrap()
-        .to_string();
-    mpv_free_node_contents(addr_of_mut!(result));
+}
+
+impl Options {
+    fn new(opts: &HashMap<String, String>) -> Self {
+        let mut options = Self::default();
+        if let Some(font_size) = opts
+            .get("font_size")
+            .and_then(|s| s.parse().ok().filter(|&s| s > 0.))
+        {
+            options.font_size = font_size;
+        }
+        if let Some(fixed_duration) = opts
+            .get("fixed_duration")
+            .and_then(|s| s.parse().ok().filter(|&s| s > 0.))
+        {
+            options.fixed_duration = fixed_duration;
+        }
+        if let Some(reverse) = opts.get("reverse") {
+            options.reverse = reverse == "yes";
+        }
+        if let Some(match_mode) = opts.get("match_mode") {
+            match match_mode.parse() {
+                Ok(match_mode) => options.match_mode = match_mode,
+                Err(error) => unsafe { log_error(error) },
+            }
+        }
+        if let Some(ch_convert) = opts.get("ch_convert") {
+            match ch_convert.parse() {
+                Ok(ch_convert) => options.ch_convert = ch_convert,
+                Err(error) => unsafe { log_error(error) },
+            }
+        }
+        if let Some(sources) = opts.get("disabled_sources") {
+            options.disabled_sources = sources
+                .split(',')
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .filter_map(|s| match s.parse() {
+                    Ok(source) => Some(source),
+                    Err(error) => {
+                        unsafe { log_error(error) };
+                        None
+                    }
+                })
+                .collect();
+        }
+        if let Some(token) = opts.get("token").filter(|s| !s.is_empty()) {
+            options.token = Some(token.clone());
+        }
+        if let Some(history_threshold) = opts
+            .get("history_threshold")
+            .and_then(|s| s.parse().ok().filter(|&s| (0. ..=100.).contains(&s)))
+        {
+            options.history_threshold = history_threshold;
+        }
+        if let Some(connect_timeout) = opts
+            .get("connect_timeout")
+            .and_then(|s| s.parse().ok().filter(|&s| s > 0.))
+        {
+            options.connect_timeout = Duration::from_secs_f64(connect_timeout);
+        }
+        if let Some(read_timeout) = opts
+            .get("read_timeout")
+            .and_then(|s| s.parse().ok().filter(|&s| s > 0.))
+        {
+            options.read_timeout = Duration::from_secs_f64(read_timeout);
+        }
+        if let Some(retries) = opts.get("retries").and_then(|s| s.parse().ok()) {
+            options.retries = retries;
+        }
+        if let Some(proxy) = opts.get("proxy").filter(|s| !s.is_empty()) {
+            options.proxy = Some(proxy.clone());
+        }
+        if let Some(user_agent) = opts.get("user_agent").filter(|s| !s.is_empty()) {
+            options.user_agent = user_agent.clone();
+        }
+        if let Some(language) = opts.get("language") {
+            match language.parse() {
+                Ok(language) => options.language = language,
+                Err(error) => unsafe { log_error(error) },
+            }
+        }
+        if let Some(cache_ttl) = opts.get("cache_ttl").and_then(|s| s.parse().ok()) {
+            options.cache_ttl = cache_ttl;
+        }
+        if let Some(cache_expiry) = opts.get("cache_expiry").and_then(|s| s.parse::<f64>().ok()) {
+            options.cache_expiry =
+                (cache_expiry > 0.).then(|| Duration::from_secs_f64(cache_expiry * 24. * 3600.));
+        }
+        if let Some(prefetch) = opts.get("prefetch").and_then(|s| s.parse().ok()) {
+            options.prefetch = prefetch;
+        }
+        if let Some(refresh_interval) = opts
+            .get("refresh_interval")
+            .and_then(|s| s.parse().ok().filter(|&s| s > 0.))
+        {
+            options.refresh_interval = Some(Duration::from_secs_f64(refresh_interval));
+        }
+        options
+    }
+}
+
+static OPTIONS: OnceLock<Options> = OnceLock::new();
+
+pub fn options() -> &'static Options {
+    OPTIONS.get_or_init(Options::default)
+}
+
+pub unsafe fn init_options() {
+    let opts = read_options()
+        .map_err(|e| log_error(e))
+        .ok()
+        .flatten()
+        .unwrap_or_default();
+    _ = OPTIONS.set(Options::new(&opts));
+}
 
+unsafe fn read_options() -> Result<Option<HashMap<String, String>>> {
+    let client_name = CLIENT_NAME;
+    let path = expand_path(&format!("~~/script-opts/{}.conf", client_name))?;
     let file = match File::open(path) {
         Ok(file) => file,
         Err(error) if error.kind() == ErrorKind::NotFound => return Ok(None),
@@ -48,3 +196,26 @@ pub unsafe fn read_options() -> Result<Option<HashMap<String, String>>> {
     }
     Ok(Some(opts))
 }
+
+#[allow(clippy::uninit_assumed_init)]
+#[allow(invalid_value)]
+pub unsafe fn expand_path(path: &str) -> Result<String> {
+    let arg2 = CString::new(path).map_err(io::Error::from)?;
+    let mut args = [c"expand-path".as_ptr(), arg2.as_ptr(), null()];
+    let mut result = MaybeUninit::<mpv_node>::uninit().assume_init();
+    let error = mpv_command_ret(CTX, args.as_mut_ptr(), addr_of_mut!(result));
+    if error < 0 {
+        return Err(Error::Mpv(
+            CStr::from_ptr(mpv_error_string(error))
+                .to_string_lossy()
+                .into(),
+        ));
+    }
+    assert_eq!(result.format, mpv_format::MPV_FORMAT_STRING);
+    let path = CStr::from_ptr(result.u.string)
+        .to_str()
+        .unwrap()
+        .to_string();
+    mpv_free_node_contents(addr_of_mut!(result));
+    Ok(path)
+}
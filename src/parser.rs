@@ -0,0 +1,405 @@
+const EXTENSIONS: [&str; 14] = [
+    "mkv", "mp4", "avi", "webm", "flv", "ts", "m2ts", "mov", "wmv", "rmvb", "m4v", "mpg", "mpeg",
+    "ogm",
+];
+
+const KEYWORDS: [&str; 38] = [
+    "bd", "bdrip", "bdremux", "bluray", "blu-ray", "dvd", "dvdrip", "web", "web-dl", "webrip",
+    "tv", "tvrip", "hdtv", "hevc", "avc", "x264", "x265", "h264", "h.264", "h265", "h.265",
+    "10bit", "8bit", "hi10p", "flac", "aac", "ac3", "dts", "opus", "gb", "big5", "chs", "cht",
+    "sc", "tc", "jp", "raw", "end",
+];
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Release {
+    pub title: String,
+    pub episode: Option<u32>,
+    pub season: Option<u32>,
+    pub group: Option<String>,
+    pub tags: Vec<String>,
+}
+
+impl Release {
+    /// The title and episode number without any release decorations, used in
+    /// place of the raw file name when matching, or `None` if no title was
+    /// found.
+    pub fn name(&self) -> Option<String> {
+        if self.title.is_empty() {
+            return None;
+        }
+        Some(match self.episode {
+            Some(episode) => format!("{} {:02}", self.title, episode),
+            None => self.title.clone(),
+        })
+    }
+}
+
+pub fn parse(file_name: &str) -> Release {
+    let name = match file_name.rsplit_once('.') {
+        Some((name, extension)) if EXTENSIONS.contains(&&*extension.to_lowercase()) => name,
+        _ => file_name,
+    };
+
+    let mut release = Release::default();
+    let mut text = String::new();
+    let mut brackets = Vec::new();
+    let mut chars = name.chars();
+    while let Some(c) = chars.next() {
+        let close = match c {
+            '[' => ']',
+            '(' => ')',
+            '【' => '】',
+            '（' => '）',
+            _ => {
+                text.push(c);
+                continue;
+            }
+        };
+        let content = chars
+            .by_ref()
+            .take_while(|&c| c != close)
+            .collect::<String>();
+        let content = content.trim();
+        if content.is_empty() {
+            continue;
+        }
+        if release.group.is_none() && text.trim().is_empty() && brackets.is_empty() && c != '(' {
+            release.group = Some(content.to_string());
+        } else {
+            brackets.push(content.to_string());
+        }
+        text.push(' ');
+    }
+
+    let text = text.replace('_', " ");
+    let text = if text.trim().contains(' ') {
+        text
+    } else {
+        text.replace('.', " ")
+    };
+    let tokens = text
+        .split(|c: char| c.is_whitespace() || c == '\u{3000}')
+        .filter(|token| !token.is_empty())
+        .collect::<Vec<_>>();
+
+    let mut title = Vec::new();
+    let mut rest = Vec::new();
+    let mut bare = None;
+    let mut i = 0;
+    while i < tokens.len() {
+        let token = tokens[i];
+        let next = tokens.get(i + 1).copied();
+        if let Some((season, episode)) = parse_season_episode(token) {
+            release.season = Some(season);
+            release.episode = Some(episode);
+        } else if let Some(season) = parse_season(token, next) {
+            release.season = Some(season);
+            if !token.starts_with(['S', 's', '第']) {
+                i += 1;
+            }
+        } else if token.eq_ignore_ascii_case("season") && next.and_then(parse_number).is_some() {
+            release.season = next.and_then(parse_number);
+            i += 1;
+        } else if token == "-"
+            && (release.episode.is_none() || bare.is_some())
+            && next.and_then(parse_episode).is_some()
+        {
+            // A bare number followed by "- <episode>" is part of the title.
+            if let Some(bare) = bare.take() {
+                title.push(bare);
+                title.append(&mut rest);
+            }
+            release.episode = next.and_then(parse_episode);
+            i += 1;
+        } else if release.episode.is_none() && !title.is_empty() {
+            if let Some(episode) = parse_marked_episode(token) {
+                release.episode = Some(episode);
+            } else if let Some(episode) = parse_episode(token).filter(|_| rest.is_empty()) {
+                release.episode = Some(episode);
+                bare = Some(token);
+            } else if rest.is_empty() && release.season.is_none() && !is_tag(token) {
+                title.push(token);
+            } else {
+                rest.push(token);
+            }
+        } else if title.is_empty() && release.episode.is_none() && release.season.is_none() {
+            title.push(token);
+        } else {
+            rest.push(token);
+        }
+        i += 1;
+    }
+    while title.last().is_some_and(|token| is_separator(token)) {
+        title.pop();
+    }
+
+    if release.group.is_none() {
+        if let Some((tag, group)) = rest
+            .last()
+            .and_then(|token| token.rsplit_once('-'))
+            .filter(|(tag, group)| is_tag(tag) && !group.is_empty())
+        {
+            rest.pop();
+            rest.push(tag);
+            release.group = Some(group.to_string());
+        }
+    }
+    release.tags.extend(
+        rest.into_iter()
+            .filter(|token| !is_separator(token))
+            .map(String::from),
+    );
+
+    let mut title = title.join(" ");
+    for content in brackets {
+        if release.episode.is_none() {
+            if let Some(episode) =
+                parse_marked_episode(&content).or_else(|| parse_episode(&content))
+            {
+                release.episode = Some(episode);
+                continue;
+            }
+        }
+        let tokens = content
+            .split(|c: char| c.is_whitespace() || c == ',')
+            .filter(|token| !token.is_empty());
+        if title.is_empty() && !tokens.clone().any(is_tag) {
+            title = content;
+        } else {
+            release.tags.extend(tokens.map(String::from));
+        }
+    }
+    release.title = title;
+    release
+}
+
+fn parse_number(s: &str) -> Option<u32> {
+    if !s.is_empty() && s.len() <= 4 && s.bytes().all(|b| b.is_ascii_digit()) {
+        s.parse().ok()
+    } else {
+        None
+    }
+}
+
+fn parse_episode(s: &str) -> Option<u32> {
+    let s = match s.find(['v', 'V']) {
+        Some(i) if parse_number(&s[i + 1..]).is_some() => &s[..i],
+        _ => s,
+    };
+    parse_number(s).filter(|&n| n < 1900)
+}
+
+fn parse_marked_episode(s: &str) -> Option<u32> {
+    if let Some(s) = s
+        .strip_prefix('第')
+        .and_then(|s| s.strip_suffix(['话', '話', '集']))
+    {
+        return parse_number(s);
+    }
+    let lower = s.to_lowercase();
+    ["ep.", "ep", "e", "#"]
+        .into_iter()
+        .find_map(|prefix| lower.strip_prefix(prefix))
+        .and_then(parse_episode)
+}
+
+fn parse_season_episode(s: &str) -> Option<(u32, u32)> {
+    let lower = s.to_lowercase();
+    let (season, episode) = lower.strip_prefix('s')?.split_once('e')?;
+    Some((parse_number(season)?, parse_episode(episode)?))
+}
+
+fn parse_season(s: &str, next: Option<&str>) -> Option<u32> {
+    if let Some(season) = s.strip_prefix('第').and_then(|s| s.strip_suffix('季')) {
+        return parse_number(season).or_else(|| {
+            let mut chars = season.chars();
+            let c = chars.next().filter(|_| chars.next().is_none())?;
+            "一二三四五六七八九十"
+                .chars()
+                .position(|numeral| numeral == c)
+                .map(|i| i as u32 + 1)
+        });
+    }
+    if let Some(season) = s.strip_prefix(['S', 's']) {
+        return parse_number(season);
+    }
+    if next.is_some_and(|next| next.eq_ignore_ascii_case("season")) {
+        let lower = s.to_lowercase();
+        return ["st", "nd", "rd", "th"]
+            .into_iter()
+            .find_map(|suffix| lower.strip_suffix(suffix))
+            .and_then(parse_number);
+    }
+    None
+}
+
+fn is_separator(s: &str) -> bool {
+    s.chars().all(|c| "-~|".contains(c))
+}
+
+fn is_tag(s: &str) -> bool {
+    let lower = s.to_lowercase();
+    if KEYWORDS.contains(&&*lower) {
+        return true;
+    }
+    if let Some(resolution) = lower.strip_suffix(['p', 'i']) {
+        if matches!(resolution, "480" | "576" | "720" | "1080" | "2160") {
+            return true;
+        }
+    }
+    if let Some((width, height)) = lower.split_once('x') {
+        if parse_number(width).is_some() && parse_number(height).is_some() {
+            return true;
+        }
+    }
+    s.len() == 8
+        && s.bytes().all(|b| b.is_ascii_hexdigit())
+        && s.bytes().any(|b| b.is_ascii_digit())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn release(
+        title: &str,
+        episode: Option<u32>,
+        season: Option<u32>,
+        group: Option<&str>,
+        tags: &[&str],
+    ) -> Release {
+        Release {
+            title: title.into(),
+            episode,
+            season,
+            group: group.map(String::from),
+            tags: tags.iter().map(|&tag| tag.into()).collect(),
+        }
+    }
+
+    #[test]
+    fn fansub() {
+        assert_eq!(
+            parse("[Group] Title - 05 (BD 1080p) [ABCD1234].mkv"),
+            release(
+                "Title",
+                Some(5),
+                None,
+                Some("Group"),
+                &["BD", "1080p", "ABCD1234"]
+            )
+        );
+        assert_eq!(
+            parse("[SubsPlease] Sousou no Frieren - 12 (1080p) [A1B2C3D4].mkv"),
+            release(
+                "Sousou no Frieren",
+                Some(12),
+                None,
+                Some("SubsPlease"),
+                &["1080p", "A1B2C3D4"]
+            )
+        );
+        assert_eq!(
+            parse("[Group] Title S2 - 05v2 [1080p].mkv"),
+            release("Title", Some(5), Some(2), Some("Group"), &["1080p"])
+        );
+        assert_eq!(
+            parse("[Group] Title 2nd Season - 03 [WEB 1080p HEVC].mp4"),
+            release(
+                "Title",
+                Some(3),
+                Some(2),
+                Some("Group"),
+                &["WEB", "1080p", "HEVC"]
+            )
+        );
+        assert_eq!(
+            parse("[Group] 86 - 05 [1080p].mkv"),
+            release("86", Some(5), None, Some("Group"), &["1080p"])
+        );
+    }
+
+    #[test]
+    fn bracketed_title() {
+        assert_eq!(
+            parse("[Group][Title][05][1080p][CHS].mp4"),
+            release("Title", Some(5), None, Some("Group"), &["1080p", "CHS"])
+        );
+        assert_eq!(
+            parse("【Group】【Title】【第05话】【1080P】【简日双语】.mp4"),
+            release(
+                "Title",
+                Some(5),
+                None,
+                Some("Group"),
+                &["1080P", "简日双语"]
+            )
+        );
+    }
+
+    #[test]
+    fn chinese() {
+        assert_eq!(
+            parse("[Group] 标题 第二季 第05话 [1080p].mkv"),
+            release("标题", Some(5), Some(2), Some("Group"), &["1080p"])
+        );
+        assert_eq!(
+            parse("标题 第2季 第12集.mp4"),
+            release("标题", Some(12), Some(2), None, &[])
+        );
+    }
+
+    #[test]
+    fn scene() {
+        assert_eq!(
+            parse("Title.Name.S01E05.1080p.WEB-DL.x264-GROUP.mkv"),
+            release(
+                "Title Name",
+                Some(5),
+                Some(1),
+                Some("GROUP"),
+                &["1080p", "WEB-DL", "x264"]
+            )
+        );
+        assert_eq!(
+            parse("Title_Name_-_07_[720p].mkv"),
+            release("Title Name", Some(7), None, None, &["720p"])
+        );
+    }
+
+    #[test]
+    fn plain() {
+        assert_eq!(
+            parse("Title 05.mkv"),
+            release("Title", Some(5), None, None, &[])
+        );
+        assert_eq!(
+            parse("Title EP05.mp4"),
+            release("Title", Some(5), None, None, &[])
+        );
+        assert_eq!(
+            parse("Title 2 - 05.mkv"),
+            release("Title 2", Some(5), None, None, &[])
+        );
+        assert_eq!(
+            parse("Title Season 3 - 10.mkv"),
+            release("Title", Some(10), Some(3), None, &[])
+        );
+        assert_eq!(
+            parse("Movie (2019).mkv"),
+            release("Movie", None, None, None, &["2019"])
+        );
+        assert_eq!(parse("Title"), release("Title", None, None, None, &[]));
+    }
+
+    #[test]
+    fn name() {
+        assert_eq!(
+            parse("[Group] Title - 5 [1080p].mkv").name().as_deref(),
+            Some("Title 05")
+        );
+        assert_eq!(parse("Movie (2019).mkv").name().as_deref(), Some("Movie"));
+        assert_eq!(parse("[Group][05].mkv").name(), None);
+        assert_eq!(parse("[ABCD1234].mkv").name(), None);
+    }
+}